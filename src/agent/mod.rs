@@ -0,0 +1,104 @@
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::Config;
+use crate::triggers::heartbeat::HeartbeatScheduler;
+use crate::triggers::idle::IdleMonitor;
+use crate::triggers::system;
+use crate::triggers::usb::UsbMonitor;
+use crate::utils;
+use crate::webhook::outbox::Outbox;
+use crate::webhook::{Notifier, WebhookSender};
+use reload::Reloadable;
+
+mod reload;
+
+/// How often the outbox is checked for deliveries that are due for a retry
+const OUTBOX_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Run the agent until a shutdown signal is received.
+///
+/// Starts every trigger on the current tokio runtime, sends the boot
+/// notification and stops the triggers again on SIGTERM/SIGINT.
+pub async fn run(config: Config) -> Result<()> {
+    run_until(config, || {}, utils::shutdown_signal()).await
+}
+
+/// Run the agent until `shutdown` completes, calling `started` once every
+/// trigger is running
+pub async fn run_until<S, F>(config: Config, started: S, shutdown: F) -> Result<()>
+where
+    S: FnOnce(),
+    F: Future<Output = Result<()>>,
+{
+    log::info!("Starting RAA agent for device {}", config.device_name);
+    
+    let outbox = Outbox::open(Outbox::default_dir()?).await?;
+    let webhook = Arc::new(WebhookSender::from_config(&config)?.with_outbox(outbox));
+    let notifier: Arc<dyn Notifier> = webhook.clone();
+    
+    let retry_task = tokio::spawn(retry_outbox(Arc::clone(&webhook)));
+    
+    let boot_notifier = Arc::clone(&notifier);
+    tokio::spawn(async move {
+        system::send_boot_notification(boot_notifier.as_ref()).await.unwrap_or_else(|e| {
+            log::error!("Failed to send boot notification: {}", e);
+        });
+    });
+    
+    let usb_monitor = UsbMonitor::new(Arc::clone(&notifier));
+    if let Err(e) = usb_monitor.start_monitoring() {
+        log::warn!("USB monitoring unavailable: {}", e);
+    }
+    
+    let idle_monitor = Arc::new(IdleMonitor::new(Arc::clone(&notifier), config.idle_threshold));
+    idle_monitor.start_monitoring()?;
+    
+    let heartbeat = Arc::new(HeartbeatScheduler::new(notifier, config.ping_interval));
+    heartbeat.start().await?;
+    
+    let reloadable = Reloadable {
+        webhook,
+        heartbeat: Arc::clone(&heartbeat),
+        idle_monitor: Arc::clone(&idle_monitor),
+    };
+    let reload_task = tokio::spawn(async move {
+        if let Err(e) = reload::watch_config(reloadable, config).await {
+            log::warn!("Config hot reload unavailable: {:#}", e);
+        }
+    });
+    
+    started();
+    
+    shutdown.await?;
+    log::info!("Shutdown requested, stopping RAA agent");
+    
+    reload_task.abort();
+    retry_task.abort();
+    heartbeat.stop().await?;
+    idle_monitor.stop();
+    usb_monitor.stop();
+    
+    Ok(())
+}
+
+/// Periodically replay queued webhook deliveries
+async fn retry_outbox(webhook: Arc<WebhookSender>) {
+    let mut interval = tokio::time::interval(OUTBOX_RETRY_INTERVAL);
+    
+    loop {
+        interval.tick().await;
+        
+        match webhook.flush_outbox().await {
+            Ok(stats) if stats.delivered > 0 || stats.rejected > 0 || stats.expired > 0 => {
+                log::info!(
+                    "Outbox: {} delivered, {} rejected, {} expired, {} still pending",
+                    stats.delivered, stats.rejected, stats.expired, stats.remaining
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to process outbox: {:#}", e),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{Context, Result};
+use notify::{Event as FsEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::config::{self, Config, ParseError};
+use crate::triggers::heartbeat::HeartbeatScheduler;
+use crate::triggers::idle::IdleMonitor;
+use crate::webhook::{Event, EventCategory, Notifier, Severity, WebhookSender};
+
+/// Quiet time after the last change before the file is read; editors often
+/// write a file in several steps
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Parts of the running agent that take new settings from a reload
+pub struct Reloadable {
+    pub webhook: Arc<WebhookSender>,
+    pub heartbeat: Arc<HeartbeatScheduler>,
+    pub idle_monitor: Arc<IdleMonitor>,
+}
+
+/// Watch the user and system config files and apply every valid change to
+/// the running agent.
+///
+/// The directories are watched rather than the files, so changes are still
+/// seen when an editor replaces a file instead of writing it in place.
+pub async fn watch_config(agent: Reloadable, mut current: Config) -> Result<()> {
+    let config_paths: Vec<_> = std::iter::once(config::get_config_path()?)
+        .chain(config::get_system_config_path())
+        .collect();
+    
+    let watched_paths = config_paths.clone();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |result: notify::Result<FsEvent>| match result {
+            Ok(event) if event.paths.iter().any(|path| watched_paths.contains(path)) => {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Config watch error: {}", e),
+        },
+        notify::Config::default(),
+    )?;
+    
+    for config_path in &config_paths {
+        let config_dir = config_path.parent()
+            .context("Config path has no parent directory")?;
+        
+        // Users of a system config may not have a config directory
+        if !config_dir.exists() {
+            continue;
+        }
+        
+        watcher.watch(config_dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", config_dir))?;
+        
+        log::info!("Watching {:?} for changes", config_path);
+    }
+    
+    while rx.recv().await.is_some() {
+        // Wait for the writes to settle
+        while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {}
+        
+        // A file may be missing for a moment while it is being replaced
+        if !config_paths.iter().any(|path| path.exists()) {
+            continue;
+        }
+        
+        match apply(&agent, &current).await {
+            Ok(Some(config)) => current = config,
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("Keeping the current config, reload failed: {:#}", e);
+                report(&agent, Event::new(
+                    EventCategory::SYSTEM,
+                    "config_reload_failed",
+                    "Config Reload Failed",
+                    "The changed config file was rejected, the agent keeps running with the previous config",
+                )
+                .with_fields(vec![("Error".to_string(), failure_summary(&e))])
+                .with_severity(Severity::Warning)).await;
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Load the changed file and apply it, returning `None` if nothing changed
+async fn apply(agent: &Reloadable, current: &Config) -> Result<Option<Config>> {
+    apply_config(agent, current, config::reload_config()?).await
+}
+
+async fn apply_config(agent: &Reloadable, current: &Config, config: Config) -> Result<Option<Config>> {
+    // Comparing the serialized form ignores formatting-only edits
+    if serde_json::to_value(&config)? == serde_json::to_value(current)? {
+        return Ok(None);
+    }
+    
+    // Everything that can fail is prepared first, so a rejected config
+    // leaves the agent as it was instead of half updated
+    let settings = WebhookSender::prepare_reload(&config)?;
+    let interval = agent.heartbeat.prepare_interval(config.ping_interval).await?;
+    
+    // Starting the new heartbeat schedule is the only step left that can fail
+    agent.heartbeat.apply_interval(interval).await?;
+    agent.webhook.apply_reload(settings);
+    agent.idle_monitor.set_threshold(config.idle_threshold);
+    
+    log::info!("Config reloaded");
+    report(agent, Event::new(
+        EventCategory::SYSTEM,
+        "config_reloaded",
+        "Config Reloaded",
+        "The agent applied the changed config file",
+    ).with_fields(vec![
+        ("Heartbeat Interval".to_string(), format!("{} minutes", config.ping_interval)),
+        ("Idle Threshold".to_string(), format!("{} minutes", config.idle_threshold)),
+    ])).await;
+    
+    Ok(Some(config))
+}
+
+/// The error as sent in notifications. Parse errors are reduced to their
+/// location and message, without the line of the file they quote.
+fn failure_summary(error: &anyhow::Error) -> String {
+    match error.chain().find_map(|cause| cause.downcast_ref::<ParseError>()) {
+        Some(parse_error) => parse_error.summary(),
+        None => format!("{:#}", error),
+    }
+}
+
+async fn report(agent: &Reloadable, event: Event) {
+    if let Err(e) = agent.webhook.notify(&event).await {
+        log::error!("Failed to send {} notification: {:#}", event.kind, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::format::ConfigFormat;
+    use crate::webhook::test_server::{response, serve};
+    use serde_json::json;
+    use std::path::Path;
+    
+    fn config(url: &str, ping_interval: u64) -> Config {
+        serde_json::from_value(json!({
+            "destinations": { "alerts": url },
+            "routes": [{ "match": {}, "to": "alerts" }],
+            "ping_interval": ping_interval,
+        })).unwrap()
+    }
+    
+    fn agent(config: &Config) -> Reloadable {
+        let webhook = Arc::new(WebhookSender::from_config(config).unwrap());
+        
+        Reloadable {
+            heartbeat: Arc::new(HeartbeatScheduler::new(webhook.clone(), config.ping_interval)),
+            idle_monitor: Arc::new(IdleMonitor::new(webhook.clone(), config.idle_threshold)),
+            webhook,
+        }
+    }
+    
+    #[tokio::test]
+    async fn applies_changed_configs() {
+        let (old_url, old_server) = serve(vec![]).await;
+        let (new_url, new_server) = serve(vec![response("204 No Content", &[], "")]).await;
+        let current = config(&old_url, 15);
+        let agent = agent(&current);
+        
+        let applied = apply_config(&agent, &current, config(&new_url, 30)).await.unwrap();
+        assert_eq!(applied.unwrap().ping_interval, 30);
+        
+        // The reload is announced through the new destinations only
+        let requests = new_server.await.unwrap();
+        assert!(requests[0].contains("Config Reloaded"), "{}", requests[0]);
+        assert!(old_server.await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn ignores_unchanged_configs() {
+        let (url, server) = serve(vec![]).await;
+        let current = config(&url, 15);
+        let agent = agent(&current);
+        
+        assert!(apply_config(&agent, &current, config(&url, 15)).await.unwrap().is_none());
+        assert!(server.await.unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn keeps_the_current_config_when_preparing_fails() {
+        let (old_url, old_server) = serve(vec![response("204 No Content", &[], "")]).await;
+        let current = config(&old_url, 15);
+        let agent = agent(&current);
+        
+        let mut rejected = config("https://example.com/new", 30);
+        rejected.http.proxy = Some(crate::config::secret::Secret::new("not a url"));
+        assert!(apply_config(&agent, &current, rejected).await.is_err());
+        
+        let event = Event::new(EventCategory::SYSTEM, "test", "Still here", "Old destinations");
+        agent.webhook.notify(&event).await.unwrap();
+        assert!(old_server.await.unwrap()[0].contains("Still here"));
+    }
+    
+    #[test]
+    fn leaves_file_contents_out_of_failure_reports() {
+        let contents = "{\n  \"alerts\": \"https://example.com/hooks/secret-token\" oops\n}\n";
+        let error = ConfigFormat::Json.parse::<serde_json::Value>(Path::new("config.json"), contents).unwrap_err();
+        let error = anyhow::Error::new(error).context("Failed to load the config");
+        assert!(format!("{:#}", error).contains("secret-token"));
+        
+        let summary = failure_summary(&error);
+        assert!(summary.starts_with("Failed to parse \"config.json\" at line"), "{}", summary);
+        assert!(!summary.contains("secret-token"), "{}", summary);
+        
+        assert_eq!(failure_summary(&anyhow::anyhow!("device_name: must not be empty")), "device_name: must not be empty");
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use crate::config::{self, Config};
+use crate::config::format::ConfigFormat;
+use crate::config::layers::{self, LayeredConfig};
+use crate::service::{self, BackgroundService};
+use crate::webhook::{Event, EventCategory, Severity, WebhookSender};
+
+const SERVICE_NAME: &str = "raa";
+const SERVICE_DISPLAY_NAME: &str = "RAA";
+const SERVICE_DESCRIPTION: &str = "Remote Access Agent - monitoring and notification agent";
+
+#[derive(Debug, Parser)]
+#[command(name = "raa", version, about = "Remote Access Agent - Cross-platform monitoring and notification agent")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Override a config value, e.g. `--set ping_interval=5` or
+    /// `--set http.proxy=http://proxy:3128`. Values from the system config
+    /// cannot be overridden.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub overrides: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Install RAA as a background service
+    Install,
+    /// Stop and remove the background service
+    Uninstall,
+    /// Start the installed background service
+    Start,
+    /// Stop the running background service
+    Stop,
+    /// Show whether the background service is installed and running
+    Status,
+    /// Run the agent in the foreground (default when no command is given)
+    Run,
+    /// Manage the configuration file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Send a test notification to the webhook of a category
+    TestWebhook {
+        /// Event category to test, e.g. system, usb or idle
+        category: EventCategory,
+        /// Severity of the test event (info, warning or critical)
+        #[arg(long, default_value = "info")]
+        severity: Severity,
+    },
+    /// Entry point used by the Windows service control manager
+    #[command(hide = true)]
+    Service {
+        /// Directory of the service's config, in place of the user's
+        config_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Write a default configuration file
+    Init {
+        /// Overwrite an existing configuration file
+        #[arg(long)]
+        force: bool,
+        /// File format to write (json, toml or yaml)
+        #[arg(long)]
+        format: Option<ConfigFormat>,
+    },
+    /// Print the merged configuration and the files it was loaded from
+    Show {
+        /// List every value with the file, variable or flag it came from
+        #[arg(long)]
+        sources: bool,
+    },
+    /// Check that the configuration file can be loaded
+    Validate,
+}
+
+/// Parse the command line and execute the requested command
+pub fn run() -> Result<()> {
+    let cli = Cli::parse();
+    layers::set_cli_overrides(&cli.overrides)?;
+    
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Install => {
+            let service = get_service()?;
+            if service.is_installed()? {
+                println!("{} service is already installed", SERVICE_DISPLAY_NAME);
+                return Ok(());
+            }
+            
+            // The service does not run as the installing user
+            #[cfg(target_os = "windows")]
+            config::set_config_dir(config::get_service_config_dir()?)?;
+            
+            // Make sure the service finds a config on its first start
+            let config_path = config::get_config_path()?;
+            if !config_path.exists() {
+                config::create_default_config()?;
+                println!("Default config written to {}", config_path.display());
+                println!("Replace the placeholder webhook URLs before starting the service");
+            }
+            
+            service.install()?;
+            println!("{} service installed", SERVICE_DISPLAY_NAME);
+        }
+        Command::Uninstall => {
+            let service = get_service()?;
+            if service.is_running()? {
+                service.stop()?;
+            }
+            service.uninstall()?;
+            println!("{} service uninstalled", SERVICE_DISPLAY_NAME);
+        }
+        Command::Start => {
+            get_service()?.start()?;
+            println!("{} service started", SERVICE_DISPLAY_NAME);
+        }
+        Command::Stop => {
+            get_service()?.stop()?;
+            println!("{} service stopped", SERVICE_DISPLAY_NAME);
+        }
+        Command::Status => {
+            let service = get_service()?;
+            let installed = service.is_installed()?;
+            let running = installed && service.is_running()?;
+            
+            println!("Installed: {}", if installed { "yes" } else { "no" });
+            println!("Running:   {}", if running { "yes" } else { "no" });
+        }
+        Command::Run => {
+            let config = config::ensure_config_exists()?;
+            
+            new_runtime()?.block_on(crate::agent::run(config))?;
+        }
+        Command::Service { config_dir } => {
+            if let Some(config_dir) = config_dir {
+                config::set_config_dir(config_dir)?;
+            }
+            
+            #[cfg(target_os = "windows")]
+            service::windows::run_service_dispatcher(SERVICE_NAME)?;
+            
+            #[cfg(not(target_os = "windows"))]
+            anyhow::bail!("The service command is only used by the Windows service control manager");
+        }
+        Command::Config(command) => run_config_command(command)?,
+        Command::TestWebhook { category, severity } => {
+            let config = Config::load()?;
+            let webhook = WebhookSender::from_config(&config)?;
+            
+            let event = Event::new(
+                category,
+                "test",
+                "Test Notification",
+                "This is a test notification sent by `raa test-webhook`.",
+            )
+            .with_fields(vec![("Device".to_string(), config.device_name.clone())])
+            .with_severity(severity);
+            
+            let report = new_runtime()?.block_on(webhook.send_event(&event));
+            
+            if report.outcomes.is_empty() {
+                anyhow::bail!("No destinations configured for {} events", event.category);
+            }
+            
+            for outcome in &report.outcomes {
+                println!("{}: {}", outcome.destination, outcome.outcome);
+            }
+            
+            report.into_result()?;
+        }
+    }
+    
+    Ok(())
+}
+
+fn run_config_command(command: ConfigCommand) -> Result<()> {
+    let config_path = config::get_config_path()?;
+    
+    match command {
+        ConfigCommand::Init { force, format } => {
+            let config_path = match format {
+                Some(format) => config::get_config_dir()?.join(format!("config.{}", format.extension())),
+                None => config_path,
+            };
+            
+            if config_path.exists() && !force {
+                anyhow::bail!("Config file already exists at {:?} (use --force to overwrite)", config_path);
+            }
+            
+            config::default_config().save_to(&config_path)?;
+            println!("Default config written to {}", config_path.display());
+            
+            // Only the first file in lookup order is loaded
+            let loaded_path = config::get_config_path()?;
+            if loaded_path != config_path {
+                println!("Note: {} takes precedence over it", loaded_path.display());
+            }
+        }
+        ConfigCommand::Show { sources } => {
+            // Show invalid configs too, they are what needs looking at
+            let layered = LayeredConfig::load(&config_path)?;
+            
+            for layer in &layered.layers {
+                println!("# {}", layer);
+            }
+            
+            if sources {
+                for (path, value, source) in layered.values()? {
+                    println!("{} = {}  # {}", path, value, source);
+                }
+            } else {
+                let format = ConfigFormat::from_path(&config_path).unwrap_or(ConfigFormat::Json);
+                println!("{}", format.serialize(&layered.config)?);
+            }
+        }
+        ConfigCommand::Validate => {
+            let (_, issues) = Config::load_with_issues()?;
+            
+            for issue in &issues {
+                println!("{}", issue);
+            }
+            
+            let errors = issues.iter().filter(|issue| issue.is_error()).count();
+            if errors > 0 {
+                anyhow::bail!("Config at {} has {} error(s)", config_path.display(), errors);
+            }
+            
+            println!("Config at {} is valid", config_path.display());
+        }
+    }
+    
+    Ok(())
+}
+
+fn new_runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Runtime::new().context("Failed to start tokio runtime")
+}
+
+fn get_service() -> Result<Box<dyn BackgroundService>> {
+    let executable_path = std::env::current_exe()
+        .context("Failed to determine path of the raa executable")?;
+    
+    service::get_service(SERVICE_NAME, SERVICE_DISPLAY_NAME, SERVICE_DESCRIPTION, executable_path)
+}
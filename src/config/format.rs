@@ -0,0 +1,263 @@
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use serde_path_to_error::Segment;
+use std::path::Path;
+use super::ParseError;
+use crate::utils;
+
+/// File formats the config can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Config file names in the order they are looked up
+    pub const FILE_NAMES: [&'static str; 4] = ["config.toml", "config.yaml", "config.yml", "config.json"];
+    
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+    
+    /// Format of a config file from its name. Backups such as
+    /// `config.toml.last-good` keep the format of the file they were taken of.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        file_name.split('.').skip(1).find_map(|part| part.parse().ok())
+    }
+    
+    /// Deserialize `contents`, reporting the location of syntax and type errors
+    pub fn parse<T: DeserializeOwned>(&self, path: &Path, contents: &str) -> Result<T, ParseError> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| {
+                let message = strip_location(&e.to_string(), e.line(), e.column());
+                ParseError::new(path, contents, e.line(), e.column(), message)
+            }),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+                let (line, column) = e.span()
+                    .map(|span| line_and_column(contents, span.start))
+                    .unwrap_or((1, 1));
+                ParseError::new(path, contents, line, column, e.message().to_string())
+            }),
+            ConfigFormat::Yaml => serde_norway::from_str(contents).map_err(|e| {
+                let (line, column) = e.location()
+                    .map(|location| (location.line(), location.column()))
+                    .unwrap_or((1, 1));
+                let message = strip_location(&e.to_string(), line, column);
+                ParseError::new(path, contents, line, column, message)
+            }),
+        }
+    }
+    
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String> {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(value).context("Failed to serialize config to JSON"),
+            ConfigFormat::Toml => toml::to_string_pretty(value).context("Failed to serialize config to TOML"),
+            ConfigFormat::Yaml => serde_norway::to_string(value).context("Failed to serialize config to YAML"),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err(anyhow::anyhow!("Unknown config format '{}' (expected json, toml or yaml)", s)),
+        }
+    }
+}
+
+/// Deserialize `document`, already parsed from `contents`, reporting type
+/// errors with the key they concern and where that key is in the file
+pub fn deserialize<T: DeserializeOwned>(path: &Path, contents: &str, document: &Value) -> Result<T, ParseError> {
+    serde_path_to_error::deserialize(document).map_err(|e| {
+        let (line, column) = locate(contents, e.path())
+            .map(|offset| line_and_column(contents, offset))
+            .unwrap_or((1, 1));
+        let message = match e.path().iter().next() {
+            Some(_) => format!("{}: {}", e.path(), e.inner()),
+            None => e.inner().to_string(),
+        };
+        ParseError::new(path, contents, line, column, message)
+    })
+}
+
+/// Byte offset of the innermost key of `path` in `contents`, found by
+/// looking for each key after the previous one. This works for JSON, YAML
+/// and TOML tables alike; list indices are not located.
+fn locate(contents: &str, path: &serde_path_to_error::Path) -> Option<usize> {
+    let mut offset = None;
+    
+    for segment in path.iter() {
+        let Segment::Map { key } = segment else {
+            continue;
+        };
+        
+        let from = offset.map_or(0, |offset| offset + 1);
+        offset = Some(contents[from..].match_indices(key.as_str())
+            .map(|(index, _)| from + index)
+            .find(|&index| is_key_at(contents, index, key))?);
+    }
+    
+    offset
+}
+
+/// Whether `key` at `index` is a key, not part of a longer name or a value
+fn is_key_at(contents: &str, index: usize, key: &str) -> bool {
+    let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let before = contents[..index].chars().next_back();
+    let after = contents[index + key.len()..].trim_start_matches(['"', '\'', ' ', '\t']).chars().next();
+    
+    !before.is_some_and(is_name) && matches!(after, Some(':' | '=' | '.' | ']'))
+}
+
+/// serde_json and serde_norway include the location in their messages
+fn strip_location(message: &str, line: usize, column: usize) -> String {
+    message.replacen(&format!(" at line {} column {}", line, column), "", 1)
+}
+
+/// 1-based line and column of a byte offset
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..utils::floor_char_boundary(contents, offset)];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    
+    fn type_error(format: ConfigFormat, contents: &str) -> ParseError {
+        let path = Path::new("config");
+        let document: Value = format.parse(path, contents).unwrap();
+        deserialize::<Config>(path, contents, &document).unwrap_err()
+    }
+    
+    #[test]
+    fn locates_type_errors_at_their_key() {
+        let json = "{\n  \"ping_interval\": 5,\n  \"http\": {\n    \"timeout\": 1,\n    \"request_timeout\": \"soon\"\n  }\n}\n";
+        let toml = "ping_interval = 5\n\n[http]\nrequest_timeout = \"soon\"\n";
+        let yaml = "ping_interval: 5\nhttp:\n  request_timeout: soon\n";
+        
+        for (format, contents, line, column) in [
+            (ConfigFormat::Json, json, 5, 6),
+            (ConfigFormat::Toml, toml, 4, 1),
+            (ConfigFormat::Yaml, yaml, 3, 3),
+        ] {
+            let error = type_error(format, contents);
+            assert_eq!((error.line, error.column), (line, column), "{}", format);
+            assert!(error.message.starts_with("http.request_timeout: invalid type"), "{}", error.message);
+        }
+    }
+    
+    #[test]
+    fn skips_values_and_longer_names() {
+        let json = r#"{ "device_name": "http", "http_extra": { "connect_timeout": 1 }, "http": { "connect_timeout": "soon" } }"#;
+        let error = type_error(ConfigFormat::Json, json);
+        
+        assert_eq!(error.column - 1, json.rfind("connect_timeout").unwrap());
+    }
+    
+    fn load(format: ConfigFormat, contents: &str) -> Value {
+        let path = Path::new("config");
+        let document: Value = format.parse(path, contents).unwrap();
+        let config: Config = deserialize(path, contents, &document).unwrap();
+        serde_json::to_value(config).unwrap()
+    }
+    
+    #[test]
+    fn reads_every_format() {
+        let json = r#"{
+            "config_version": 2,
+            "device_name": "pc",
+            "ping_interval": 5,
+            "destinations": {
+                "ops": "https://example.com/ops",
+                "phone": { "url": "https://ntfy.sh/raa", "format": "ntfy" }
+            },
+            "routes": [{ "match": { "category": ["usb", "idle"] }, "to": ["ops", "phone"] }],
+            "http": { "proxy": "http://proxy:3128" }
+        }"#;
+        let toml = r#"
+            config_version = 2
+            device_name = "pc"
+            ping_interval = 5
+            
+            [destinations]
+            ops = "https://example.com/ops"
+            phone = { url = "https://ntfy.sh/raa", format = "ntfy" }
+            
+            [[routes]]
+            match = { category = ["usb", "idle"] }
+            to = ["ops", "phone"]
+            
+            [http]
+            proxy = "http://proxy:3128"
+        "#;
+        let yaml = "
+config_version: 2
+device_name: pc
+ping_interval: 5
+destinations:
+  ops: https://example.com/ops
+  phone:
+    url: https://ntfy.sh/raa
+    format: ntfy
+routes:
+  - match: { category: [usb, idle] }
+    to: [ops, phone]
+http:
+  proxy: http://proxy:3128
+";
+
+        let expected = load(ConfigFormat::Json, json);
+        assert_eq!(expected["destinations"]["phone"]["url"], "https://ntfy.sh/raa");
+        assert_eq!(load(ConfigFormat::Toml, toml), expected);
+        assert_eq!(load(ConfigFormat::Yaml, yaml), expected);
+    }
+    
+    #[test]
+    fn round_trips_every_format() {
+        let expected = serde_json::to_value(crate::config::default_config()).unwrap();
+        
+        for format in [ConfigFormat::Json, ConfigFormat::Toml, ConfigFormat::Yaml] {
+            let contents = format.serialize(&expected).unwrap();
+            assert_eq!(load(format, &contents), expected, "{}", format);
+        }
+    }
+    
+    #[test]
+    fn locates_syntax_errors() {
+        let path = Path::new("config");
+        
+        for (format, contents, line, column) in [
+            (ConfigFormat::Toml, "device_name = \"pc\"\nping_interval = = 5\n", 2, 17),
+            (ConfigFormat::Yaml, "device_name: pc\nhttp:\n  proxy: [unclosed\n", 4, 1),
+        ] {
+            let error = format.parse::<Value>(path, contents).unwrap_err();
+            assert_eq!((error.line, error.column), (line, column), "{}: {}", format, error.message);
+            assert!(!error.message.contains(&format!("at line {} column {}", line, column)), "{}", error.message);
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use super::format::{self, ConfigFormat};
+use super::migration;
+use super::validation::{self, ConfigIssue, ValueType};
+use super::{check_permissions, check_system_permissions, get_system_config_path, Config};
+
+/// Prefix of environment variables overriding config values, e.g.
+/// `RAA_PING_INTERVAL=5`
+const ENV_PREFIX: &str = "RAA_";
+
+/// Separates nested keys in environment variable names, e.g.
+/// `RAA_HTTP__PROXY` sets `http.proxy`
+const ENV_SEPARATOR: &str = "__";
+
+/// `--set` flags given on the command line
+static CLI_OVERRIDES: OnceLock<Vec<(String, Value)>> = OnceLock::new();
+
+/// Where a config value came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// Not set anywhere, the built-in default
+    Default,
+    /// The admin-controlled system-wide file
+    System(PathBuf),
+    /// The user's config file
+    User(PathBuf),
+    /// An `RAA_*` environment variable
+    Env(String),
+    /// A `--set` flag
+    Cli(String),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::System(path) => write!(f, "system file {}", path.display()),
+            ConfigSource::User(path) => write!(f, "user file {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "environment variable {}", name),
+            ConfigSource::Cli(key) => write!(f, "--set {}", key),
+        }
+    }
+}
+
+/// Record the `--set KEY=VALUE` flags of this process, applied on top of
+/// every config loaded afterwards
+pub fn set_cli_overrides(flags: &[String]) -> Result<()> {
+    let overrides = flags.iter()
+        .map(|flag| match flag.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                migration::check_override_key(key.trim()).with_context(|| format!("Invalid --set '{}'", flag))?;
+                Ok((key.trim().to_string(), parse_value(key.trim(), value)))
+            }
+            _ => Err(anyhow::anyhow!("Invalid --set '{}', expected KEY=VALUE", flag)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    
+    CLI_OVERRIDES.set(overrides)
+        .map_err(|_| anyhow::anyhow!("Config overrides were already set"))
+}
+
+/// The config merged from all of its layers, with the source of each value
+#[derive(Debug)]
+pub struct LayeredConfig {
+    pub config: Config,
+    /// Problems found in the merged settings
+    pub issues: Vec<ConfigIssue>,
+    /// Every layer that contributed, lowest precedence first
+    pub layers: Vec<ConfigSource>,
+    /// Source of each value set by a layer, by path
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl LayeredConfig {
+    /// Merge the system file, the user file at `user_path`, `RAA_*`
+    /// environment variables and `--set` flags, in that order.
+    ///
+    /// Values from the system file cannot be replaced by later layers: its
+    /// tables only gain keys and its lists are only extended, so users can
+    /// add destinations and routes but not change or remove the admin's.
+    /// Its destinations and `http` table are sealed and gain no keys either.
+    pub fn load(user_path: &Path) -> Result<Self> {
+        let mut merger = Merger::default();
+        
+        let mut system = get_system_config_path()
+            .map(|path| {
+                check_system_permissions(&path);
+                ConfigFile::read(&path)
+            })
+            .transpose()?;
+        
+        // With a system file the agent can run without a user file
+        let mut user = None;
+        if system.is_none() || user_path.exists() {
+            check_permissions(user_path);
+            user = Some(ConfigFile::read(user_path)?);
+        }
+        
+        // Destinations created by upgrading one file must not take the names
+        // of the other's, or merging would replace them
+        if let Some(system) = &mut system {
+            let taken = user.as_ref().map(ConfigFile::destination_names).unwrap_or_default();
+            system.upgrade(&taken, false)?;
+            merger.merge(system.document.clone(), ConfigSource::System(system.path.clone()));
+        }
+        if let Some(user) = &mut user {
+            let taken = system.as_ref().map(ConfigFile::destination_names).unwrap_or_default();
+            user.upgrade(&taken, true)?;
+            merger.merge(user.document.clone(), ConfigSource::User(user.path.clone()));
+        }
+        
+        for (name, key, value) in env_overrides() {
+            migration::check_override_key(&key).with_context(|| format!("Invalid environment variable {}", name))?;
+            merger.merge(nest(&key, value), ConfigSource::Env(name));
+        }
+        
+        for (key, value) in CLI_OVERRIDES.get().into_iter().flatten() {
+            merger.merge(nest(key, value.clone()), ConfigSource::Cli(key.clone()));
+        }
+        
+        let mut config: Config = serde_json::from_value(merger.value.clone())
+            .with_context(|| format!("Invalid config after applying {}", merger.layers.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")))?;
+        
+        let mut issues = merger.issues;
+        issues.extend(validation::unknown_keys(&merger.value));
+        issues.extend(config.resolve_secrets());
+        issues.extend(config.validate());
+        
+        Ok(Self {
+            config,
+            issues,
+            layers: merger.layers,
+            sources: merger.sources,
+        })
+    }
+    
+    /// Every value of the merged config, including defaults, with the layer
+    /// it came from
+    pub fn values(&self) -> Result<Vec<(String, Value, &ConfigSource)>> {
+        let mut leaves = Vec::new();
+        collect_leaves(&mut leaves, String::new(), serde_json::to_value(&self.config)?);
+        
+        Ok(leaves.into_iter()
+            .map(|(path, value)| {
+                let source = self.source_of(&path);
+                (path, value, source)
+            })
+            .collect())
+    }
+    
+    fn source_of(&self, path: &str) -> &ConfigSource {
+        // Values may be written in a different shape than they are shown,
+        // e.g. a destination given as a table and shown as a plain URL
+        let ancestor = self.sources.iter()
+            .filter(|(key, _)| is_within(path, key))
+            .max_by_key(|(key, _)| key.len());
+        let descendant = self.sources.iter().find(|(key, _)| is_within(key, path));
+        
+        ancestor.or(descendant)
+            .map(|(_, source)| source)
+            .unwrap_or(&ConfigSource::Default)
+    }
+}
+
+/// A config file of one layer
+struct ConfigFile {
+    path: PathBuf,
+    format: ConfigFormat,
+    contents: String,
+    document: Value,
+}
+
+impl ConfigFile {
+    /// Parse a config file, reporting the location of syntax errors
+    fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to open config file at {:?}", path))?;
+        
+        let format = ConfigFormat::from_path(path).unwrap_or(ConfigFormat::Json);
+        let document = format.parse(path, &contents)?;
+        
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            contents,
+            document,
+        })
+    }
+    
+    fn destination_names(&self) -> BTreeSet<String> {
+        self.document.get("destinations")
+            .and_then(Value::as_object)
+            .map(|destinations| destinations.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+    
+    /// Upgrade a file from an older version, then check it for type errors
+    /// pointing into the file.
+    ///
+    /// Destinations it gains avoid the names in `taken`. With `save` the
+    /// upgraded document replaces the file, except for YAML; the system file
+    /// is left to the admin.
+    fn upgrade(&mut self, taken: &BTreeSet<String>, save: bool) -> Result<()> {
+        let path = &self.path;
+        let version = migration::version(&self.document)
+            .with_context(|| format!("Invalid config file {:?}", path))?;
+        
+        if migration::migrate(&mut self.document, taken).with_context(|| format!("Failed to upgrade {:?}", path))? {
+            if !save {
+                log::warn!("{:?} uses config version {}, upgrade it to version {}", path, version, migration::CURRENT_VERSION);
+            } else {
+                match migration::upgrade_file(path, self.format, &self.contents, &self.document, version) {
+                    Ok(Some(upgraded)) => self.contents = upgraded,
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Using the upgraded config without saving it: {:#}", e),
+                }
+            }
+        }
+        
+        format::deserialize::<Config>(path, &self.contents, &self.document)?;
+        
+        Ok(())
+    }
+}
+
+/// `RAA_*` variables as (name, dotted key, value), sorted by name
+fn env_overrides() -> Vec<(String, String, Value)> {
+    let mut overrides: Vec<_> = std::env::vars_os()
+        .filter_map(|(name, value)| env_override(name.into_string().ok()?, &value.into_string().ok()?))
+        .collect();
+    overrides.sort_by(|a, b| a.0.cmp(&b.0));
+    
+    overrides
+}
+
+/// The override set by an environment variable. Variables that do not name
+/// a config key are left alone, such as those holding secrets for
+/// `env:RAA_...` references; keys removed by an upgrade are kept to be
+/// reported.
+fn env_override(name: String, value: &str) -> Option<(String, String, Value)> {
+    let key = name.strip_prefix(ENV_PREFIX)?
+        .split(ENV_SEPARATOR)
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(".");
+    
+    if validation::value_type(&key).is_none() && migration::removed_key(&key).is_none() {
+        return None;
+    }
+    
+    let value = parse_value(&key, value);
+    Some((name, key, value))
+}
+
+/// Read an override as the type its key expects: JSON numbers, lists or
+/// tables where those are expected, and a plain string otherwise, so that
+/// `device_name=1234` stays a name
+fn parse_value(key: &str, value: &str) -> Value {
+    let expected = validation::value_type(key).unwrap_or(ValueType::String);
+    
+    serde_json::from_str(value).ok()
+        .filter(|parsed: &Value| match expected {
+            ValueType::String => false,
+            ValueType::Number => parsed.is_number(),
+            ValueType::List => parsed.is_array(),
+            ValueType::Table => parsed.is_object(),
+        })
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+/// Wrap `value` in tables for each part of a dotted key
+fn nest(key: &str, value: Value) -> Value {
+    key.rsplit('.').fold(value, |value, part| {
+        let mut table = Map::new();
+        table.insert(part.to_string(), value);
+        Value::Object(table)
+    })
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+/// Whether `key` is `path` or a value nested under it
+fn is_within(key: &str, path: &str) -> bool {
+    key.strip_prefix(path).is_some_and(|rest| rest.is_empty() || rest.starts_with('.') || rest.starts_with('['))
+}
+
+fn collect_leaves(leaves: &mut Vec<(String, Value)>, path: String, value: Value) {
+    match value {
+        Value::Object(table) if !table.is_empty() => {
+            for (key, value) in table {
+                collect_leaves(leaves, join(&path, &key), value);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, value) in items.into_iter().enumerate() {
+                collect_leaves(leaves, format!("{}[{}]", path, index), value);
+            }
+        }
+        value => leaves.push((path, value)),
+    }
+}
+
+/// The table `path` is in if the system file owns that table as a whole
+/// when it sets it: one of its destinations, or the HTTP settings
+fn sealed_table(path: &str) -> Option<String> {
+    let mut parts = path.split('.');
+    
+    match (parts.next(), parts.next()) {
+        (Some("http"), _) => Some("http".to_string()),
+        (Some("destinations"), Some(name)) => Some(format!("destinations.{}", name)),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Merger {
+    value: Value,
+    sources: BTreeMap<String, ConfigSource>,
+    layers: Vec<ConfigSource>,
+    issues: Vec<ConfigIssue>,
+}
+
+impl Merger {
+    fn merge(&mut self, layer: Value, source: ConfigSource) {
+        let mut value = std::mem::replace(&mut self.value, Value::Object(Map::new()));
+        self.merge_value(&mut value, layer, "", &source);
+        self.value = value;
+        self.layers.push(source);
+    }
+    
+    fn merge_value(&mut self, target: &mut Value, layer: Value, path: &str, source: &ConfigSource) {
+        match (target, layer) {
+            (Value::Object(target), Value::Object(layer)) => {
+                for (key, value) in layer {
+                    let key_path = join(path, &key);
+                    match target.get_mut(&key) {
+                        Some(existing) => self.merge_value(existing, value, &key_path, source),
+                        None => {
+                            if let Some(sealed) = sealed_table(&key_path).filter(|sealed| self.is_system(sealed)) {
+                                self.issues.push(ConfigIssue::warning(&key_path, format!("{} is set by the system config, ignoring the key added by {}", sealed, source)));
+                                continue;
+                            }
+                            self.record(&key_path, &value, source);
+                            target.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (Value::Array(target), Value::Array(layer)) if self.is_system(path) => {
+                for value in layer {
+                    self.record(&format!("{}[{}]", path, target.len()), &value, source);
+                    target.push(value);
+                }
+            }
+            (target, layer) if self.is_system(path) => {
+                if *target != layer {
+                    self.issues.push(ConfigIssue::warning(path, format!("set by the system config, ignoring the value from {}", source)));
+                }
+            }
+            (target, layer) => {
+                self.sources.retain(|key, _| !is_within(key, path));
+                self.record(path, &layer, source);
+                *target = layer;
+            }
+        }
+    }
+    
+    fn record(&mut self, path: &str, value: &Value, source: &ConfigSource) {
+        match value {
+            Value::Object(table) if !table.is_empty() => {
+                for (key, value) in table {
+                    self.record(&join(path, key), value, source);
+                }
+            }
+            Value::Array(items) if !items.is_empty() => {
+                for (index, value) in items.iter().enumerate() {
+                    self.record(&format!("{}[{}]", path, index), value, source);
+                }
+            }
+            _ => {
+                self.sources.insert(path.to_string(), source.clone());
+            }
+        }
+    }
+    
+    /// Whether the system file set `path` or anything under it
+    fn is_system(&self, path: &str) -> bool {
+        self.sources.iter().any(|(key, source)| is_within(key, path) && matches!(source, ConfigSource::System(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn system() -> ConfigSource {
+        ConfigSource::System(PathBuf::from("/etc/raa/config.toml"))
+    }
+    
+    fn user() -> ConfigSource {
+        ConfigSource::User(PathBuf::from("config.toml"))
+    }
+    
+    fn issue_paths(merger: &Merger) -> Vec<&str> {
+        merger.issues.iter().map(|issue| issue.path.as_str()).collect()
+    }
+    
+    #[test]
+    fn later_layers_take_precedence() {
+        let mut merger = Merger::default();
+        merger.merge(json!({ "device_name": "pc", "ping_interval": 5, "idle_threshold": 10 }), user());
+        merger.merge(json!({ "ping_interval": 7, "idle_threshold": 20 }), ConfigSource::Env("RAA_PING_INTERVAL".to_string()));
+        merger.merge(json!({ "ping_interval": 9 }), ConfigSource::Cli("ping_interval".to_string()));
+        
+        assert_eq!(merger.value, json!({ "device_name": "pc", "ping_interval": 9, "idle_threshold": 20 }));
+        assert_eq!(merger.sources["device_name"], user());
+        assert_eq!(merger.sources["ping_interval"], ConfigSource::Cli("ping_interval".to_string()));
+        assert_eq!(merger.sources["idle_threshold"], ConfigSource::Env("RAA_PING_INTERVAL".to_string()));
+        assert!(merger.issues.is_empty());
+    }
+    
+    #[test]
+    fn keeps_system_values() {
+        let mut merger = Merger::default();
+        merger.merge(json!({
+            "device_name": "corp",
+            "destinations": { "ops": "https://example.com/ops" },
+            "routes": [{ "match": { "severity": "critical" }, "to": "ops" }],
+        }), system());
+        merger.merge(json!({
+            "device_name": "mine",
+            "ping_interval": 5,
+            "destinations": { "ops": "https://example.com/mine", "phone": "https://ntfy.sh/raa" },
+            "routes": [{ "match": { "category": "usb" }, "to": "phone" }],
+        }), user());
+        
+        assert_eq!(merger.value, json!({
+            "device_name": "corp",
+            "destinations": { "ops": "https://example.com/ops", "phone": "https://ntfy.sh/raa" },
+            "routes": [
+                { "match": { "severity": "critical" }, "to": "ops" },
+                { "match": { "category": "usb" }, "to": "phone" },
+            ],
+            "ping_interval": 5,
+        }));
+        assert_eq!(merger.sources["routes[1].to"], user());
+        assert_eq!(issue_paths(&merger), ["device_name", "destinations.ops"]);
+    }
+    
+    #[test]
+    fn seals_system_destinations_and_http() {
+        let mut merger = Merger::default();
+        merger.merge(json!({
+            "destinations": { "ops": { "url": "https://example.com/ops" } },
+            "http": { "proxy": "http://proxy:3128" },
+        }), system());
+        merger.merge(json!({
+            "destinations": {
+                "ops": { "secret": "mine", "format": "slack" },
+                "phone": { "url": "https://ntfy.sh/raa", "format": "ntfy" },
+            },
+            "http": { "request_timeout": 5, "client_certificate": { "certificate": "me.pem" } },
+            "idle_threshold": 10,
+        }), user());
+        
+        assert_eq!(merger.value, json!({
+            "destinations": {
+                "ops": { "url": "https://example.com/ops" },
+                "phone": { "url": "https://ntfy.sh/raa", "format": "ntfy" },
+            },
+            "http": { "proxy": "http://proxy:3128" },
+            "idle_threshold": 10,
+        }));
+        assert_eq!(issue_paths(&merger), [
+            "destinations.ops.secret",
+            "destinations.ops.format",
+            "http.request_timeout",
+            "http.client_certificate",
+        ]);
+        assert!(merger.issues[0].message.starts_with("destinations.ops is set by the system config"), "{}", merger.issues[0]);
+    }
+    
+    #[test]
+    fn lets_users_fill_tables_the_system_leaves_open() {
+        let mut merger = Merger::default();
+        merger.merge(json!({ "destinations": { "ops": "https://example.com/ops" } }), system());
+        merger.merge(json!({ "destinations": { "phone": { "url": "https://ntfy.sh/raa" } } }), user());
+        merger.merge(json!({ "destinations": { "phone": { "secret": "key" } } }), ConfigSource::Env("RAA_DESTINATIONS__PHONE__SECRET".to_string()));
+        merger.merge(json!({ "http": { "proxy": "http://proxy:3128" } }), ConfigSource::Cli("http.proxy".to_string()));
+        
+        assert_eq!(merger.value["destinations"]["phone"], json!({ "url": "https://ntfy.sh/raa", "secret": "key" }));
+        assert_eq!(merger.value["http"], json!({ "proxy": "http://proxy:3128" }));
+        assert!(merger.issues.is_empty());
+    }
+    
+    #[test]
+    fn reads_overrides_as_the_expected_type() {
+        for (key, value, expected) in [
+            ("device_name", "1234", json!("1234")),
+            ("device_name", "true", json!("true")),
+            ("ping_interval", "5", json!(5)),
+            ("ping_interval", "soon", json!("soon")),
+            ("http.proxy", "http://proxy:3128", json!("http://proxy:3128")),
+            ("http.ca_certificates", r#"["a.pem", "b.pem"]"#, json!(["a.pem", "b.pem"])),
+            ("destinations.ops", "https://example.com/ops", json!("https://example.com/ops")),
+            ("destinations.ops", r#"{"url": "https://example.com/ops"}"#, json!({ "url": "https://example.com/ops" })),
+            ("destinations.ops.kinds", r#"["usb_*"]"#, json!(["usb_*"])),
+            ("destinations.ops.secret", "42", json!("42")),
+            ("unknown", "5", json!("5")),
+        ] {
+            assert_eq!(parse_value(key, value), expected, "{}={}", key, value);
+        }
+    }
+    
+    #[test]
+    fn maps_only_variables_of_known_keys() {
+        let env = |name: &str, value: &str| env_override(name.to_string(), value).map(|(_, key, value)| (key, value));
+        
+        assert_eq!(env("RAA_DEVICE_NAME", "1234"), Some(("device_name".to_string(), json!("1234"))));
+        assert_eq!(env("RAA_HTTP__REQUEST_TIMEOUT", "5"), Some(("http.request_timeout".to_string(), json!(5))));
+        assert_eq!(env("RAA_DESTINATIONS__OPS__URL", "https://example.com/ops"), Some(("destinations.ops.url".to_string(), json!("https://example.com/ops"))));
+        
+        // Kept so that loading reports what replaced them
+        assert!(env("RAA_WEBHOOKS__USB", "https://example.com/usb").is_some());
+        
+        for name in ["RAA_OPS_TOKEN", "RAA_HTTP__PROXY_PASSWORD", "RAA_DESTINATIONS__OPS__TOKEN", "HOME"] {
+            assert_eq!(env(name, "secret"), None, "{}", name);
+        }
+    }
+}
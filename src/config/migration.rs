@@ -0,0 +1,614 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use toml_edit::{ArrayOfTables, Decor, DocumentMut, Item, Key, Table};
+use super::format::ConfigFormat;
+use crate::utils;
+
+/// Version of the config format written by this build. Files without a
+/// `config_version` key are version 1.
+pub const CURRENT_VERSION: u64 = 2;
+
+/// Upgrades a document from one version to the next. Destinations it adds
+/// must not take the names in the set, which other config layers use.
+type Migration = fn(&mut Map<String, Value>, &BTreeSet<String>) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades version `n + 1` to `n + 2`
+const MIGRATIONS: &[Migration] = &[webhooks_to_destinations];
+
+const _: () = assert!(MIGRATIONS.len() as u64 == CURRENT_VERSION - 1);
+
+/// Top-level keys dropped by a migration, with what replaced them
+const REMOVED_KEYS: &[(&str, &str)] = &[("webhooks", "destinations and routes")];
+
+/// Version of a raw config document
+pub fn version(document: &Value) -> Result<u64> {
+    match document.get("config_version") {
+        None => Ok(1),
+        Some(version) => version.as_u64()
+            .filter(|version| *version >= 1)
+            .with_context(|| format!("config_version must be a positive whole number, got {}", version)),
+    }
+}
+
+/// Upgrade `document` to the current version, returning whether it changed.
+///
+/// Destinations created by the upgrade avoid the names in `taken`, so a file
+/// upgraded next to another config layer does not replace its destinations.
+pub fn migrate(document: &mut Value, taken: &BTreeSet<String>) -> Result<bool> {
+    let version = version(document)?;
+    if version > CURRENT_VERSION {
+        anyhow::bail!("Config version {} is newer than this version of RAA supports ({})", version, CURRENT_VERSION);
+    }
+    if version == CURRENT_VERSION {
+        return Ok(false);
+    }
+    
+    let table = document.as_object_mut()
+        .context("The config must be a table")?;
+    
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        migration(table, taken)
+            .with_context(|| format!("Failed to upgrade config from version {} to {}", index + 1, index + 2))?;
+    }
+    
+    // Keep the version at the top of the file
+    let mut upgraded = Map::new();
+    upgraded.insert("config_version".to_string(), CURRENT_VERSION.into());
+    upgraded.extend(std::mem::take(table).into_iter().filter(|(key, _)| key != "config_version"));
+    *table = upgraded;
+    
+    Ok(true)
+}
+
+/// Fail for an override of a key that no longer exists, e.g.
+/// `RAA_WEBHOOKS__USB`, pointing to the keys that replaced it
+pub fn check_override_key(key: &str) -> Result<()> {
+    match removed_key(key) {
+        Some((removed, replacement)) => anyhow::bail!(
+            "{} cannot be overridden: {} was replaced by {} in config version 2",
+            key, removed, replacement
+        ),
+        None => Ok(()),
+    }
+}
+
+/// The removed key, with what replaced it, that `key` is or is under
+pub fn removed_key(key: &str) -> Option<&'static (&'static str, &'static str)> {
+    let top_level = key.split('.').next().unwrap_or_default();
+    REMOVED_KEYS.iter().find(|(removed, _)| *removed == top_level)
+}
+
+/// Replace an outdated config file with its upgraded contents, keeping the
+/// original next to it as `<name>.v<version>-<timestamp>`. Returns the new
+/// contents, or `None` for YAML files: they are left for the user to upgrade,
+/// as rewriting them would drop their comments.
+pub fn upgrade_file(path: &Path, format: ConfigFormat, original: &str, upgraded: &Value, version: u64) -> Result<Option<String>> {
+    let contents = match format {
+        ConfigFormat::Json => format.serialize(upgraded)?,
+        ConfigFormat::Toml => upgrade_toml(original, upgraded)?,
+        ConfigFormat::Yaml => {
+            let replaced: Vec<_> = REMOVED_KEYS.iter()
+                .map(|(removed, replacement)| format!("replace {} with {}", removed, replacement))
+                .collect();
+            log::warn!(
+                "{:?} uses config version {}, which is still supported. YAML files are not upgraded automatically \
+                 so that their comments are kept: set `config_version: {}` and {} by hand, using `raa config show` \
+                 as a reference",
+                path, version, CURRENT_VERSION, replaced.join(", ")
+            );
+            return Ok(None);
+        }
+    };
+    
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let timestamp = chrono::Local::now().format("%Y%m%dT%H%M%S");
+    let backup_path = path.with_file_name(format!("{}.v{}-{}", file_name, version, timestamp));
+    
+    utils::write_atomic(&backup_path, original.as_bytes())
+        .context("Failed to back up the config before upgrading it")?;
+    utils::write_atomic(path, contents.as_bytes())?;
+    
+    log::info!("Upgraded {:?} to config version {}, the original is kept at {:?}", path, CURRENT_VERSION, backup_path);
+    
+    Ok(Some(contents))
+}
+
+/// Rewrite a TOML document to `upgraded`, keeping the comments and layout
+/// of every key the upgrade left unchanged
+fn upgrade_toml(original: &str, upgraded: &Value) -> Result<String> {
+    let mut document: DocumentMut = original.parse().context("Failed to parse the config for upgrading")?;
+    let before: Value = toml::from_str(original).context("Failed to parse the config for upgrading")?;
+    let fresh: DocumentMut = ConfigFormat::Toml.serialize(upgraded)?.parse()
+        .context("Failed to parse the upgraded config")?;
+    
+    let (Some(before), Some(after)) = (before.as_object(), upgraded.as_object()) else {
+        anyhow::bail!("The config must be a table");
+    };
+    
+    merge_table(document.as_table_mut(), before, after, fresh.as_table());
+    renumber_tables(document.as_table_mut(), &mut 0);
+    
+    Ok(document.to_string())
+}
+
+/// Give `table`, parsed from `before`, the keys of `after` in their order.
+/// Unchanged keys keep their formatting, tables present on both sides are
+/// merged key by key, and other values are taken from `fresh`, the
+/// serialized `after`. Comments of dropped keys move to the first key added
+/// in their place.
+fn merge_table(table: &mut Table, before: &Map<String, Value>, after: &Map<String, Value>, fresh: &Table) {
+    let names: Vec<String> = table.iter().map(|(name, _)| name.to_string()).collect();
+    let mut entries: Vec<(Key, Item)> = names.iter().filter_map(|name| table.remove_entry(name)).collect();
+    let mut comments: BTreeMap<&str, String> = BTreeMap::new();
+    
+    // A comment separated from the first key by a blank line heads the table
+    if let (Some((key, item)), Some(first)) = (entries.first_mut(), after.keys().next()) {
+        if !names.contains(first) {
+            if let Some(decor) = decor_mut(key, item) {
+                let prefix = decor.prefix().and_then(|prefix| prefix.as_str()).unwrap_or_default().to_string();
+                if let Some(end) = prefix.rfind("\n\n") {
+                    comments.insert(first, prefix[..end + 2].to_string());
+                    decor.set_prefix(&prefix[end + 2..]);
+                }
+            }
+        }
+    }
+    
+    for (index, (key, item)) in entries.iter_mut().enumerate() {
+        if after.contains_key(&names[index]) {
+            continue;
+        }
+        let start = names[..index].iter().rev()
+            .find_map(|kept| after.keys().position(|name| name == kept))
+            .map_or(0, |position| position + 1);
+        let heir = after.keys().skip(start).find(|name| !names.contains(name));
+        let comment = decor_mut(key, item)
+            .and_then(|decor| decor.prefix()?.as_str().map(str::to_string))
+            .filter(|comment| comment.contains('#'));
+        if let (Some(heir), Some(comment)) = (heir, comment) {
+            comments.entry(heir).or_default().push_str(&comment);
+        }
+    }
+    
+    for (name, value) in after {
+        let Some(fresh_item) = fresh.get(name) else {
+            continue;
+        };
+        let kept = names.iter().position(|kept| kept == name).map(|index| {
+            let (key, item) = &mut entries[index];
+            (key.clone(), std::mem::take(item))
+        });
+        
+        let (key, item) = match (kept, before.get(name)) {
+            (Some(kept), Some(old)) if old == value => kept,
+            (Some((key, Item::Table(mut inner))), Some(Value::Object(old))) => match (value, fresh_item) {
+                (Value::Object(new), Item::Table(fresh_inner)) => {
+                    merge_table(&mut inner, old, new, fresh_inner);
+                    (key, Item::Table(inner))
+                }
+                _ => (key, fresh_item.clone()),
+            },
+            (Some((key, Item::ArrayOfTables(mut tables))), Some(Value::Array(old))) => match (value, fresh_item) {
+                (Value::Array(new), Item::ArrayOfTables(fresh_tables)) => {
+                    (key, Item::ArrayOfTables(merge_tables(&mut tables, old, new, fresh_tables)))
+                }
+                _ => (key, fresh_item.clone()),
+            },
+            (Some((key, _)), _) => (key, fresh_item.clone()),
+            (None, _) => {
+                let mut key = Key::new(name.as_str());
+                let mut item = fresh_item.clone();
+                if let (Some(comment), Some(decor)) = (comments.remove(name.as_str()), decor_mut(&mut key, &mut item)) {
+                    decor.set_prefix(comment);
+                }
+                (key, item)
+            }
+        };
+        table.insert_formatted(&key, item);
+    }
+}
+
+/// Lists of tables such as `[[routes]]` keep the tables whose values are
+/// unchanged, wherever they moved to
+fn merge_tables(tables: &mut ArrayOfTables, before: &[Value], after: &[Value], fresh: &ArrayOfTables) -> ArrayOfTables {
+    let mut kept: Vec<Option<Table>> = tables.iter_mut().map(|table| Some(std::mem::take(table))).collect();
+    let mut merged = ArrayOfTables::new();
+    
+    for (index, value) in after.iter().enumerate() {
+        let unchanged = before.iter().enumerate()
+            .position(|(old_index, old)| old == value && kept[old_index].is_some())
+            .and_then(|old_index| kept[old_index].take());
+        if let Some(table) = unchanged.or_else(|| fresh.get(index).cloned()) {
+            merged.push(table);
+        }
+    }
+    
+    merged
+}
+
+/// Decor holding the comments above a key
+fn decor_mut<'a>(key: &'a mut Key, item: &'a mut Item) -> Option<&'a mut Decor> {
+    match item {
+        Item::Table(table) => Some(table.decor_mut()),
+        Item::ArrayOfTables(array) => array.get_mut(0).map(Table::decor_mut),
+        Item::Value(_) => Some(key.leaf_decor_mut()),
+        Item::None => None,
+    }
+}
+
+/// Number the tables of a document in the order of their keys, which is the
+/// order they are written in. Tables kept from the original file would
+/// otherwise stay at their old place.
+fn renumber_tables(table: &mut Table, position: &mut usize) {
+    for (_, item) in table.iter_mut() {
+        let tables: Vec<&mut Table> = match item {
+            Item::Table(inner) => vec![inner],
+            Item::ArrayOfTables(array) => array.iter_mut().collect(),
+            _ => continue,
+        };
+        
+        for inner in tables {
+            *position += 1;
+            inner.set_position(*position);
+            renumber_tables(inner, position);
+        }
+    }
+}
+
+/// Version 2 drops the per-category `webhooks` shorthand: each webhook
+/// becomes a named destination, with a route for each category placed
+/// before the existing routes
+fn webhooks_to_destinations(document: &mut Map<String, Value>, taken: &BTreeSet<String>) -> Result<()> {
+    let Some(webhooks) = document.get("webhooks") else {
+        return Ok(());
+    };
+    let Value::Object(webhooks) = webhooks else {
+        anyhow::bail!("webhooks must be a table");
+    };
+    
+    let mut destinations = match document.get("destinations") {
+        None => Map::new(),
+        Some(Value::Object(destinations)) => destinations.clone(),
+        Some(_) => anyhow::bail!("destinations must be a table"),
+    };
+    let existing_routes = match document.get("routes") {
+        None => Vec::new(),
+        Some(Value::Array(routes)) => routes.clone(),
+        Some(_) => anyhow::bail!("routes must be a list"),
+    };
+    
+    let mut created: Vec<(String, &Value)> = Vec::new();
+    let mut routes = Vec::new();
+    
+    for category in ["system", "usb", "idle"] {
+        let targets = match webhooks.get(category) {
+            None => continue,
+            Some(Value::Array(targets)) => targets.iter().collect(),
+            Some(target) => vec![target],
+        };
+        
+        let mut names = Vec::new();
+        let mut added = 0;
+        for target in targets {
+            // One destination for a webhook shared by several categories
+            if let Some((name, _)) = created.iter().find(|(_, existing)| *existing == target) {
+                names.push(name.clone());
+                continue;
+            }
+            
+            added += 1;
+            let base = if added == 1 { category.to_string() } else { format!("{}-{}", category, added) };
+            let name = (1..)
+                .map(|n| if n == 1 { base.clone() } else { format!("{}-{}", base, n) })
+                .find(|name| !destinations.contains_key(name) && !taken.contains(name))
+                .unwrap_or(base);
+            
+            destinations.insert(name.clone(), target.clone());
+            created.push((name.clone(), target));
+            names.push(name);
+        }
+        
+        // A single name is written without the list, like `one_or_many` does
+        let to = match names.as_slice() {
+            [] => continue,
+            [name] => json!(name),
+            _ => json!(names),
+        };
+        routes.push(json!({ "match": { "category": category }, "to": to }));
+    }
+    
+    for key in webhooks.keys().filter(|key| !["system", "usb", "idle"].contains(&key.as_str())) {
+        log::warn!("Dropping unknown key webhooks.{} while upgrading the config", key);
+    }
+    
+    routes.extend(existing_routes);
+    
+    // Destinations and routes take the place of `webhooks` in the file
+    let mut replacement = Some((destinations, routes));
+    let mut upgraded = Map::new();
+    for (key, value) in std::mem::take(document) {
+        match key.as_str() {
+            "webhooks" => {
+                let (destinations, routes) = replacement.take().unwrap_or_default();
+                if !destinations.is_empty() {
+                    upgraded.insert("destinations".to_string(), Value::Object(destinations));
+                }
+                if !routes.is_empty() {
+                    upgraded.insert("routes".to_string(), Value::Array(routes));
+                }
+            }
+            "destinations" | "routes" => {}
+            _ => {
+                upgraded.insert(key, value);
+            }
+        }
+    }
+    *document = upgraded;
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn upgrade(document: Value) -> Value {
+        upgrade_with(document, &[])
+    }
+    
+    fn upgrade_with(mut document: Value, taken: &[&str]) -> Value {
+        let taken = taken.iter().map(|name| name.to_string()).collect();
+        assert!(migrate(&mut document, &taken).unwrap());
+        document
+    }
+    
+    #[test]
+    fn converts_webhooks() {
+        let upgraded = upgrade(json!({
+            "device_name": "pc",
+            "webhooks": {
+                "system": "https://example.com/system",
+                "usb": ["https://example.com/usb", { "url": "https://ntfy.sh/raa", "format": "ntfy" }],
+            },
+            "ping_interval": 5,
+        }));
+        
+        assert_eq!(upgraded, json!({
+            "config_version": 2,
+            "device_name": "pc",
+            "destinations": {
+                "system": "https://example.com/system",
+                "usb": "https://example.com/usb",
+                "usb-2": { "url": "https://ntfy.sh/raa", "format": "ntfy" },
+            },
+            "routes": [
+                { "match": { "category": "system" }, "to": "system" },
+                { "match": { "category": "usb" }, "to": ["usb", "usb-2"] },
+            ],
+            "ping_interval": 5,
+        }));
+        
+        // Keys keep their place in the file
+        let keys: Vec<_> = upgraded.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, ["config_version", "device_name", "destinations", "routes", "ping_interval"]);
+    }
+    
+    #[test]
+    fn shares_a_destination_between_categories() {
+        let upgraded = upgrade(json!({
+            "webhooks": {
+                "system": "https://example.com/all",
+                "usb": "https://example.com/all",
+                "idle": ["https://example.com/idle", "https://example.com/all"],
+            },
+        }));
+        
+        assert_eq!(upgraded["destinations"], json!({
+            "system": "https://example.com/all",
+            "idle": "https://example.com/idle",
+        }));
+        assert_eq!(upgraded["routes"], json!([
+            { "match": { "category": "system" }, "to": "system" },
+            { "match": { "category": "usb" }, "to": "system" },
+            { "match": { "category": "idle" }, "to": ["idle", "system"] },
+        ]));
+    }
+    
+    #[test]
+    fn avoids_existing_destination_names() {
+        let upgraded = upgrade(json!({
+            "webhooks": { "usb": ["https://example.com/usb", "https://example.com/usb2"] },
+            "destinations": { "usb": "https://example.com/existing", "usb-2-2": "https://example.com/other" },
+        }));
+        
+        assert_eq!(upgraded["destinations"], json!({
+            "usb": "https://example.com/existing",
+            "usb-2-2": "https://example.com/other",
+            "usb-2": "https://example.com/usb",
+            "usb-2-3": "https://example.com/usb2",
+        }));
+        assert_eq!(upgraded["routes"], json!([
+            { "match": { "category": "usb" }, "to": ["usb-2", "usb-2-3"] },
+        ]));
+    }
+    
+    #[test]
+    fn avoids_names_taken_by_other_layers() {
+        let upgraded = upgrade_with(json!({
+            "webhooks": { "system": "https://example.com/mine", "idle": "https://example.com/idle" },
+        }), &["system"]);
+        
+        assert_eq!(upgraded["destinations"], json!({
+            "system-2": "https://example.com/mine",
+            "idle": "https://example.com/idle",
+        }));
+        assert_eq!(upgraded["routes"][0], json!({ "match": { "category": "system" }, "to": "system-2" }));
+    }
+    
+    #[test]
+    fn places_category_routes_before_existing_routes() {
+        let upgraded = upgrade(json!({
+            "webhooks": { "idle": "https://example.com/idle" },
+            "destinations": { "alerts": "https://example.com/alerts" },
+            "routes": [{ "match": { "severity": "critical" }, "to": "alerts" }],
+        }));
+        
+        assert_eq!(upgraded["routes"], json!([
+            { "match": { "category": "idle" }, "to": "idle" },
+            { "match": { "severity": "critical" }, "to": "alerts" },
+        ]));
+    }
+    
+    #[test]
+    fn upgrades_files_without_webhooks() {
+        let upgraded = upgrade(json!({ "device_name": "pc" }));
+        
+        assert_eq!(upgraded, json!({ "config_version": 2, "device_name": "pc" }));
+    }
+    
+    #[test]
+    fn leaves_current_version_alone() {
+        let original = json!({
+            "config_version": CURRENT_VERSION,
+            "destinations": { "system": "https://example.com/system" },
+            "webhooks": { "usb": "https://example.com/usb" },
+        });
+        
+        let mut document = original.clone();
+        assert!(!migrate(&mut document, &BTreeSet::new()).unwrap());
+        assert_eq!(document, original);
+    }
+    
+    #[test]
+    fn rejects_newer_versions() {
+        let mut document = json!({ "config_version": CURRENT_VERSION + 1 });
+        
+        let error = migrate(&mut document, &BTreeSet::new()).unwrap_err();
+        assert!(error.to_string().contains("newer than this version of RAA supports"), "{}", error);
+    }
+    
+    #[test]
+    fn rejects_invalid_versions() {
+        for version in [json!(0), json!(-1), json!(1.5), json!("2")] {
+            assert!(migrate(&mut json!({ "config_version": version }), &BTreeSet::new()).is_err(), "{}", version);
+        }
+    }
+    
+    #[test]
+    fn rejects_overrides_of_removed_keys() {
+        for key in ["webhooks", "webhooks.usb"] {
+            let error = check_override_key(key).unwrap_err();
+            assert!(error.to_string().contains("replaced by destinations and routes"), "{}", error);
+        }
+        
+        check_override_key("destinations.usb.url").unwrap();
+        check_override_key("webhooks_extra").unwrap();
+    }
+    
+    const TOML_V1: &str = r#"# RAA config
+
+# Shown in notifications
+device_name = "pc"
+
+# Where events go
+[webhooks]
+system = "https://example.com/system"
+usb = ["https://example.com/usb", "https://example.com/system"]
+
+[http]
+# Behind the office proxy
+proxy = "http://proxy:3128"
+
+[[routes]]
+to = "system"  # everything critical
+[routes.match]
+severity = "critical"
+"#;
+
+    #[test]
+    fn keeps_comments_when_upgrading_toml() {
+        let mut document: Value = toml::from_str(TOML_V1).unwrap();
+        migrate(&mut document, &BTreeSet::new()).unwrap();
+        
+        let upgraded = upgrade_toml(TOML_V1, &document).unwrap();
+        assert_eq!(toml::from_str::<Value>(&upgraded).unwrap(), document);
+        assert_eq!(upgraded, r#"# RAA config
+
+config_version = 2
+# Shown in notifications
+device_name = "pc"
+
+# Where events go
+[destinations]
+system = "https://example.com/system"
+usb = "https://example.com/usb"
+
+[[routes]]
+to = "system"
+
+[routes.match]
+category = "system"
+
+[[routes]]
+to = [
+    "usb",
+    "system",
+]
+
+[routes.match]
+category = "usb"
+
+[[routes]]
+to = "system"  # everything critical
+[routes.match]
+severity = "critical"
+
+[http]
+# Behind the office proxy
+proxy = "http://proxy:3128"
+"#);
+    }
+    
+    fn upgrade_in(dir: &Path, file_name: &str, contents: &str) -> Option<String> {
+        let path = dir.join(file_name);
+        std::fs::write(&path, contents).unwrap();
+        let format = ConfigFormat::from_path(&path).unwrap();
+        let mut document: Value = format.parse(&path, contents).unwrap();
+        migrate(&mut document, &BTreeSet::new()).unwrap();
+        
+        upgrade_file(&path, format, contents, &document, 1).unwrap()
+    }
+    
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+    
+    #[test]
+    fn backs_up_upgraded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        
+        let upgraded = upgrade_in(dir.path(), "config.toml", TOML_V1).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.path().join("config.toml")).unwrap(), upgraded);
+        
+        let names = file_names(dir.path());
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[1].starts_with("config.toml.v1-"), "{:?}", names);
+        assert_eq!(std::fs::read_to_string(dir.path().join(&names[1])).unwrap(), TOML_V1);
+    }
+    
+    #[test]
+    fn leaves_yaml_files_to_the_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = "# Where events go\nwebhooks:\n  usb: https://example.com/usb\n";
+        
+        assert_eq!(upgrade_in(dir.path(), "config.yaml", contents), None);
+        assert_eq!(std::fs::read_to_string(dir.path().join("config.yaml")).unwrap(), contents);
+        assert_eq!(file_names(dir.path()), ["config.yaml"]);
+    }
+}
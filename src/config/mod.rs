@@ -1,72 +1,555 @@
 use anyhow::{Context, Result};
-use dirs::config_dir;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
-use std::io::Read;
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::{Once, OnceLock};
+use thiserror::Error;
+use crate::utils;
+use crate::webhook::{Event, PayloadFormat, Severity};
+use format::ConfigFormat;
+use layers::LayeredConfig;
+use secret::Secret;
+use validation::ConfigIssue;
 
+pub mod format;
+pub mod layers;
+pub mod migration;
+pub mod secret;
+pub mod validation;
+
+/// A webhook destination and the format its payloads are sent in.
+///
+/// Accepts either a plain URL string (Discord format) or an object with
+/// `url` and optional `format`, `secret`, `name`, `kinds` and
+/// `exclude_kinds` keys. The URL and secret may be secret references such
+/// as `env:DISCORD_WEBHOOK`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct WebhookConfig {
-    pub system: String,
-    pub usb: String,
-    pub idle: String,
+#[serde(from = "WebhookTargetSpec", into = "WebhookTargetSpec")]
+pub struct WebhookTarget {
+    pub url: Secret,
+    pub format: PayloadFormat,
+    /// Key for the HMAC-SHA256 signature header added to every request
+    pub secret: Option<Secret>,
+    /// Label used in logs and delivery reports instead of the URL
+    pub name: Option<String>,
+    /// Event kinds sent to this destination; empty means all. A trailing
+    /// `*` matches any suffix, e.g. `usb_*`
+    pub kinds: Vec<String>,
+    /// Event kinds never sent to this destination, with the same patterns
+    pub exclude_kinds: Vec<String>,
+    /// Least severe events sent to this destination; all when unset
+    pub min_severity: Option<Severity>,
+}
+
+impl WebhookTarget {
+    pub fn discord(url: &str) -> Self {
+        Self {
+            url: Secret::new(url),
+            format: PayloadFormat::Discord,
+            secret: None,
+            name: None,
+            kinds: Vec::new(),
+            exclude_kinds: Vec::new(),
+            min_severity: None,
+        }
+    }
+    
+    /// Whether the destination's filters let `event` through
+    pub fn accepts(&self, event: &Event) -> bool {
+        let included = self.kinds.is_empty()
+            || self.kinds.iter().any(|pattern| pattern_matches(pattern, &event.kind));
+        
+        included
+            && !self.exclude_kinds.iter().any(|pattern| pattern_matches(pattern, &event.kind))
+            && self.min_severity.is_none_or(|min| event.severity >= min)
+    }
+    
+    fn is_plain_discord(&self) -> bool {
+        self.format == PayloadFormat::Discord
+            && self.secret.is_none()
+            && self.name.is_none()
+            && self.kinds.is_empty()
+            && self.exclude_kinds.is_empty()
+            && self.min_severity.is_none()
+    }
+}
+
+/// Exact match, or prefix match when the pattern ends in `*`
+fn pattern_matches(pattern: &str, value: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => pattern == value,
+    }
+}
+
+/// A destination as written in the config: a plain URL or a table
+#[derive(Serialize)]
+#[serde(untagged)]
+enum WebhookTargetSpec {
+    Url(Secret),
+    Detailed(DetailedTarget),
+}
+
+#[derive(Serialize, Deserialize)]
+struct DetailedTarget {
+    url: Secret,
+    #[serde(default)]
+    format: PayloadFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<Secret>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    kinds: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude_kinds: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_severity: Option<Severity>,
+}
+
+/// Picks the form by the type of the value, so errors in a table name the
+/// key and value at fault instead of failing to match either form
+impl<'de> Deserialize<'de> for WebhookTargetSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct SpecVisitor;
+        
+        impl<'de> serde::de::Visitor<'de> for SpecVisitor {
+            type Value = WebhookTargetSpec;
+            
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a webhook URL or a table with a `url`")
+            }
+            
+            fn visit_str<E: serde::de::Error>(self, url: &str) -> std::result::Result<Self::Value, E> {
+                Ok(WebhookTargetSpec::Url(Secret::new(url)))
+            }
+            
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> std::result::Result<Self::Value, A::Error> {
+                DetailedTarget::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                    .map(WebhookTargetSpec::Detailed)
+            }
+        }
+        
+        deserializer.deserialize_any(SpecVisitor)
+    }
+}
+
+impl From<WebhookTargetSpec> for WebhookTarget {
+    fn from(spec: WebhookTargetSpec) -> Self {
+        match spec {
+            WebhookTargetSpec::Url(url) => Self { url, ..Self::discord("") },
+            WebhookTargetSpec::Detailed(DetailedTarget { url, format, secret, name, kinds, exclude_kinds, min_severity }) => {
+                Self { url, format, secret, name, kinds, exclude_kinds, min_severity }
+            }
+        }
+    }
+}
+
+impl From<WebhookTarget> for WebhookTargetSpec {
+    fn from(target: WebhookTarget) -> Self {
+        // Keep the short form for plain Discord webhooks
+        if target.is_plain_discord() {
+            return WebhookTargetSpec::Url(target.url);
+        }
+        
+        WebhookTargetSpec::Detailed(DetailedTarget {
+            url: target.url,
+            format: target.format,
+            secret: target.secret,
+            name: target.name,
+            kinds: target.kinds,
+            exclude_kinds: target.exclude_kinds,
+            min_severity: target.min_severity,
+        })
+    }
+}
+
+/// Routing rule sending matching events to named `destinations`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteRule {
+    #[serde(rename = "match", default)]
+    pub matcher: EventMatch,
+    /// Names of the destinations that receive matching events
+    #[serde(with = "one_or_many")]
+    pub to: Vec<String>,
+}
+
+/// Conditions an event has to meet; all given conditions must hold and an
+/// empty match accepts every event.
+///
+/// Values may end in `*` to match any suffix, and lists match any entry.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EventMatch {
+    #[serde(with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub category: Vec<String>,
+    #[serde(with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub kind: Vec<String>,
+    /// Least severe events matched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_severity: Option<Severity>,
+    /// Event field values by field name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl EventMatch {
+    pub fn category(category: &str) -> Self {
+        Self {
+            category: vec![category.to_string()],
+            ..Self::default()
+        }
+    }
+    
+    pub fn matches(&self, event: &Event) -> bool {
+        let category = event.category.to_string();
+        
+        matches_any(&self.category, &category)
+            && matches_any(&self.kind, &event.kind)
+            && self.min_severity.is_none_or(|min| event.severity >= min)
+            && self.fields.iter().all(|(name, pattern)| {
+                event.fields.iter().any(|(field, value)| field == name && pattern_matches(pattern, value))
+            })
+    }
+}
+
+/// Whether `value` matches one of `patterns`; no patterns match everything
+fn matches_any(patterns: &[String], value: &str) -> bool {
+    patterns.is_empty() || patterns.iter().any(|pattern| pattern_matches(pattern, value))
+}
+
+/// Accepts a single value or a list, and writes a single value back without
+/// the list so older configs keep their shape
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    
+    pub fn serialize<T: Serialize, S: Serializer>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+        match values {
+            [value] => value.serialize(serializer),
+            values => values.serialize(serializer),
+        }
+    }
+    
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        })
+    }
+}
+
+/// Settings of the HTTP client used for webhook delivery
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Seconds allowed for establishing a connection
+    pub connect_timeout: u64,
+    /// Seconds allowed for a whole request, including the response
+    pub request_timeout: u64,
+    /// Proxy for all webhook requests, e.g. `http://proxy.corp:3128`.
+    /// Without it, the `HTTPS_PROXY`/`HTTP_PROXY` environment variables apply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Secret>,
+    /// PEM files with root certificates trusted in addition to the system store
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ca_certificates: Vec<PathBuf>,
+    /// Certificate presented to endpoints that require mutual TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: 10,
+            request_timeout: 30,
+            proxy: None,
+            ca_certificates: Vec::new(),
+            client_certificate: None,
+        }
+    }
+}
+
+/// Client identity for mutual TLS.
+///
+/// Either a PEM certificate chain with a separate PKCS#8 PEM `key`, or a
+/// PKCS#12 archive (`.p12`/`.pfx`) with an optional `password`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientCertificate {
+    pub certificate: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Format version of the file, see `migration`
+    #[serde(default = "default_config_version")]
+    pub config_version: u64,
+    #[serde(default = "default_device_name")]
     pub device_name: String,
-    pub webhooks: WebhookConfig,
+    /// Named destinations referenced by `routes`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub destinations: BTreeMap<String, WebhookTarget>,
+    /// Rules selecting destinations for events
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteRule>,
+    /// Minutes between heartbeats
+    #[serde(default = "default_ping_interval")]
     pub ping_interval: u64,
+    /// Minutes without activity before an idle notification is sent
+    #[serde(default = "default_idle_threshold")]
+    pub idle_threshold: u64,
+    #[serde(default)]
+    pub http: HttpConfig,
+}
+
+fn default_config_version() -> u64 {
+    migration::CURRENT_VERSION
+}
+
+fn default_device_name() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .unwrap_or_else(|_| "Unknown Device".to_string())
+}
+
+fn default_ping_interval() -> u64 {
+    15
+}
+
+fn default_idle_threshold() -> u64 {
+    10
 }
 
 impl Config {
+    /// Load the config from the system file, the user file, `RAA_*`
+    /// environment variables and `--set` flags, logging warnings and
+    /// failing on invalid settings
     pub fn load() -> Result<Self> {
-        let config_path = get_config_path()?;
+        let (config, issues) = Self::load_with_issues()?;
+        validation::ensure_valid(issues)?;
         
-        let mut file = File::open(&config_path)
-            .with_context(|| format!("Failed to open config file at {:?}", config_path))?;
+        Ok(config)
+    }
+    
+    /// Load the config without rejecting invalid settings, returning every
+    /// problem found in it
+    pub fn load_with_issues() -> Result<(Self, Vec<ConfigIssue>)> {
+        Self::load_from(&get_config_path()?)
+    }
+    
+    /// Like `load_with_issues`, with the user config file at `path`. The
+    /// system file, environment and `--set` flags still apply.
+    pub fn load_from(path: &Path) -> Result<(Self, Vec<ConfigIssue>)> {
+        let layered = LayeredConfig::load(path)?;
+        
+        Ok((layered.config, layered.issues))
+    }
+    
+    /// Check the settings, returning every problem found
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        validation::validate(self)
+    }
+    
+    /// Look up every secret reference, returning the ones that failed
+    pub fn resolve_secrets(&mut self) -> Vec<ConfigIssue> {
+        let mut secrets: Vec<(String, &mut Secret)> = Vec::new();
         
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .context("Failed to read config file contents")?;
+        for (name, target) in &mut self.destinations {
+            let path = format!("destinations.{}", name);
+            secrets.push((format!("{}.url", path), &mut target.url));
+            if let Some(secret) = &mut target.secret {
+                secrets.push((format!("{}.secret", path), secret));
+            }
+        }
         
-        let config: Config = serde_json::from_str(&contents)
-            .context("Failed to parse config.json")?;
+        if let Some(proxy) = &mut self.http.proxy {
+            secrets.push(("http.proxy".to_string(), proxy));
+        }
         
-        Ok(config)
+        if let Some(password) = self.http.client_certificate.as_mut().and_then(|certificate| certificate.password.as_mut()) {
+            secrets.push(("http.client_certificate.password".to_string(), password));
+        }
+        
+        secrets.into_iter()
+            .filter_map(|(path, secret)| secret.resolve().err().map(|e| ConfigIssue::error(path, format!("{:#}", e))))
+            .collect()
     }
     
     pub fn save(&self) -> Result<()> {
-        let config_path = get_config_path()?;
-        
+        self.save_to(&get_config_path()?)
+    }
+    
+    pub fn save_to(&self, config_path: &Path) -> Result<()> {
         // Ensure directory exists
         if let Some(parent) = config_path.parent() {
-            fs::create_dir_all(parent)
+            utils::create_private_dir(parent)
                 .context("Failed to create config directory")?;
         }
         
-        let contents = serde_json::to_string_pretty(self)
-            .context("Failed to serialize config to JSON")?;
+        // Written back in the format of the file it came from
+        let format = ConfigFormat::from_path(config_path).unwrap_or(ConfigFormat::Json);
+        let contents = format.serialize(self)?;
         
-        fs::write(&config_path, contents)
+        utils::write_atomic(config_path, contents.as_bytes())
             .with_context(|| format!("Failed to write config file to {:?}", config_path))?;
         
         Ok(())
     }
 }
 
-fn get_config_path() -> Result<PathBuf> {
+/// Characters shown on either side of a parse error in its excerpt
+const EXCERPT_CONTEXT: usize = 40;
+
+/// The config file has a syntax error or does not have the expected structure
+#[derive(Debug, Error)]
+#[error("Failed to parse {path:?} at line {line}, column {column}: {message}\n{excerpt}")]
+pub struct ParseError {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// The offending line with a marker under the column
+    pub excerpt: String,
+}
+
+impl ParseError {
+    fn new(path: &Path, contents: &str, line: usize, column: usize, message: String) -> Self {
+        // Minified files are one long line, so only show the part around the column
+        let source_line: Vec<char> = contents.lines().nth(line.saturating_sub(1)).unwrap_or_default().chars().collect();
+        let marker = column.saturating_sub(1).min(source_line.len());
+        let start = marker.saturating_sub(EXCERPT_CONTEXT);
+        let end = (marker + EXCERPT_CONTEXT).min(source_line.len());
+        
+        let gutter = format!("{:>5} | ", line);
+        let prefix = if start > 0 { "..." } else { "" };
+        let suffix = if end < source_line.len() { "..." } else { "" };
+        let excerpt = format!(
+            "{}{}{}{}\n{}^",
+            gutter,
+            prefix,
+            source_line[start..end].iter().collect::<String>(),
+            suffix,
+            " ".repeat(gutter.len() + prefix.len() + marker - start),
+        );
+        
+        Self {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+            excerpt,
+        }
+    }
+    
+    /// The error without the excerpt, which may quote URLs or tokens from
+    /// the file
+    pub fn summary(&self) -> String {
+        format!("Failed to parse {:?} at line {}, column {}: {}", self.path, self.line, self.column, self.message)
+    }
+}
+
+/// Path of the config file: the first of `config.toml`, `config.yaml`,
+/// `config.yml` and `config.json` that exists, or `config.json` if there is
+/// no config yet
+pub fn get_config_path() -> Result<PathBuf> {
+    static WARN_IGNORED: Once = Once::new();
+    
+    let config_dir = get_config_dir()?;
+    
+    Ok(find_config_file(&config_dir, &WARN_IGNORED).unwrap_or_else(|| config_dir.join("config.json")))
+}
+
+/// Path of the admin-controlled config file shared by all users, if there
+/// is one. Its values cannot be overridden by the user's config.
+pub fn get_system_config_path() -> Option<PathBuf> {
+    static WARN_IGNORED: Once = Once::new();
+    
+    find_config_file(&get_system_config_dir()?, &WARN_IGNORED)
+}
+
+/// First config file in `dir` in lookup order, warning once about others
+fn find_config_file(dir: &Path, warn_ignored: &Once) -> Option<PathBuf> {
+    let mut existing = ConfigFormat::FILE_NAMES.iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.exists());
+    
+    let config_path = existing.next()?;
+    
+    warn_ignored.call_once(|| {
+        for ignored in existing {
+            log::warn!("Ignoring {:?}, {:?} takes precedence", ignored, config_path);
+        }
+    });
+    
+    Some(config_path)
+}
+
+/// Directory of the system-wide config file
+pub fn get_system_config_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "macos")]
+    {
+        Some(PathBuf::from("/Library/Application Support/RAA"))
+    }
+    
+    #[cfg(target_os = "windows")]
+    {
+        std::env::var_os("ProgramData").map(|path| PathBuf::from(path).join("RAA"))
+    }
+    
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    {
+        Some(PathBuf::from("/etc/raa"))
+    }
+}
+
+/// Config directory given on the command line, e.g. to the Windows service
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` instead of the per-user config directory for the rest of the
+/// process
+pub fn set_config_dir(dir: PathBuf) -> Result<()> {
+    CONFIG_DIR.set(dir)
+        .map_err(|_| anyhow::anyhow!("Config directory was already set"))
+}
+
+/// Machine-wide directory of the Windows service's config. The service runs
+/// as LocalSystem, which has a profile of its own.
+#[cfg(target_os = "windows")]
+pub fn get_service_config_dir() -> Result<PathBuf> {
+    get_system_config_dir()
+        .map(|dir| dir.join("Service"))
+        .context("Failed to determine the ProgramData directory")
+}
+
+/// Directory holding the config file and the agent's state
+pub fn get_config_dir() -> Result<PathBuf> {
+    if let Some(dir) = CONFIG_DIR.get() {
+        return Ok(dir.clone());
+    }
+    
     #[cfg(target_os = "macos")]
     {
         dirs::home_dir()
-            .map(|home| home.join("Library/Application Support/RAA/config.json"))
+            .map(|home| home.join("Library/Application Support/RAA"))
             .context("Failed to determine home directory on macOS")
     }
     
     #[cfg(target_os = "windows")]
     {
         dirs::config_dir()
-            .map(|path| path.join("RAA").join("config.json"))
+            .map(|path| path.join("RAA"))
             .context("Failed to determine config directory on Windows")
     }
     
@@ -74,53 +557,359 @@ fn get_config_path() -> Result<PathBuf> {
     {
         // Fallback for other platforms (Linux, etc.)
         dirs::config_dir()
-            .map(|path| path.join("raa").join("config.json"))
+            .map(|path| path.join("raa"))
             .context("Failed to determine config directory")
     }
 }
 
-pub fn create_default_config() -> Result<Config> {
-    let hostname = gethostname::gethostname()
-        .into_string()
-        .unwrap_or_else(|_| "Unknown Device".to_string());
-    
-    let config = Config {
-        device_name: format!("{}", hostname),
-        webhooks: WebhookConfig {
-            system: "https://discord.com/api/webhooks/system".to_string(),
-            usb: "https://discord.com/api/webhooks/usb".to_string(),
-            idle: "https://discord.com/api/webhooks/idle".to_string(),
-        },
-        ping_interval: 15,
-    };
+/// Config with placeholder webhooks for the user to fill in, one for each
+/// event category
+pub fn default_config() -> Config {
+    let categories = ["system", "usb", "idle"];
     
+    Config {
+        config_version: migration::CURRENT_VERSION,
+        device_name: default_device_name(),
+        destinations: categories.iter()
+            .map(|category| (category.to_string(), WebhookTarget::discord(&format!("https://discord.com/api/webhooks/{}", category))))
+            .collect(),
+        routes: categories.iter()
+            .map(|category| RouteRule { matcher: EventMatch::category(category), to: vec![category.to_string()] })
+            .collect(),
+        ping_interval: default_ping_interval(),
+        idle_threshold: default_idle_threshold(),
+        http: HttpConfig::default(),
+    }
+}
+
+pub fn create_default_config() -> Result<Config> {
+    let config = default_config();
     config.save()?;
+    
     Ok(config)
 }
 
 pub fn ensure_config_exists() -> Result<Config> {
     let config_path = get_config_path()?;
     
-    if config_path.exists() {
-        Config::load()
-    } else {
+    // A system config is enough to run, users need not have their own
+    if !config_path.exists() && get_system_config_path().is_none() {
         log::info!("Config file not found, creating default at {:?}", config_path);
-        create_default_config()
+        let config = create_default_config()?;
+        
+        validation::ensure_valid(config.validate())
+            .with_context(|| format!("Created a default config at {:?}, edit it and start again", config_path))?;
+        return Ok(config);
+    }
+    
+    load_or_fall_back(&config_path)
+}
+
+/// Load the config with the user file at `config_path`, falling back to the
+/// last known good copy of it when it is broken
+fn load_or_fall_back(config_path: &Path) -> Result<Config> {
+    let loaded = Config::load_from(config_path).and_then(|(config, issues)| {
+        validation::ensure_valid(issues)?;
+        Ok(config)
+    });
+    
+    match loaded {
+        Ok(config) => {
+            remember_last_good(config_path);
+            Ok(config)
+        }
+        Err(err) => {
+            if err.downcast_ref::<ParseError>().is_some_and(|e| e.path == config_path) {
+                backup_corrupt(config_path);
+            }
+            
+            // The broken file is left for the user to fix, never replaced
+            let last_good_path = last_good_path(config_path);
+            match load_last_good(&last_good_path) {
+                Some(config) => {
+                    log::error!("{:#}", err);
+                    log::error!("Starting with the last known good config from {:?} until {:?} is fixed", last_good_path, config_path);
+                    Ok(config)
+                }
+                None => Err(err),
+            }
+        }
     }
 }
-    let config_path = get_config_path()?;
+
+/// Load the config file again for the running agent.
+///
+/// Unlike `ensure_config_exists` this never falls back: an invalid file is
+/// an error and the caller keeps its current config.
+pub fn reload_config() -> Result<Config> {
+    let config = Config::load()?;
+    remember_last_good(&get_config_path()?);
     
+    Ok(config)
+}
+
+/// Warn when other users can read the user config file or its directory,
+/// which may hold webhook secrets
+fn check_permissions(config_path: &Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let paths = std::iter::once((config_path, 0o600))
+            .chain(config_path.parent().map(|dir| (dir, 0o700)));
+        
+        for (path, expected) in paths {
+            let Ok(metadata) = fs::metadata(path) else {
+                continue;
+            };
+            
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                log::warn!("{:?} is accessible by other users (mode {:o}), restrict it with `chmod {:o} {:?}`", path, mode, expected, path);
+            }
+        }
+    }
+    
+    #[cfg(not(unix))]
+    let _ = config_path;
+}
+
+/// Warn when other users can change the system config file, which would let
+/// them weaken the settings it enforces
+fn check_system_permissions(system_path: &Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        
+        if let Ok(metadata) = fs::metadata(system_path) {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o022 != 0 {
+                log::warn!("System config {:?} is writable by other users (mode {:o}), restrict it with `chmod go-w {:?}`", system_path, mode, system_path);
+            }
+        }
+    }
+    
+    #[cfg(not(unix))]
+    let _ = system_path;
+}
+
+/// Copy kept of the last config that loaded and passed validation
+fn last_good_path(config_path: &Path) -> PathBuf {
+    let file_name = config_path.file_name().unwrap_or_default().to_string_lossy();
+    config_path.with_file_name(format!("{}.last-good", file_name))
+}
+
+fn remember_last_good(config_path: &Path) {
     if !config_path.exists() {
-        log::info!("Config file not found, creating default at {:?}", config_path);
-        return create_default_config();
+        return;
     }
     
-    match Config::load() {
-        Ok(config) => Ok(config),
-        Err(err) => {
-            log::error!("Failed to load config: {}", err);
-            log::info!("Creating default config");
-            create_default_config()
+    let last_good_path = last_good_path(config_path);
+    
+    let result = fs::read(config_path)
+        .context("Failed to read config file")
+        .and_then(|contents| {
+            if fs::read(&last_good_path).ok().as_deref() == Some(contents.as_slice()) {
+                return Ok(());
+            }
+            utils::write_atomic(&last_good_path, &contents)
+        });
+    
+    if let Err(e) = result {
+        log::warn!("Failed to keep a copy of the working config: {:#}", e);
+    }
+}
+
+fn load_last_good(last_good_path: &Path) -> Option<Config> {
+    if !last_good_path.exists() {
+        return None;
+    }
+    
+    match Config::load_from(last_good_path) {
+        Ok((config, issues)) if !issues.iter().any(ConfigIssue::is_error) => Some(config),
+        Ok(_) => {
+            log::error!("Last known good config {:?} is no longer valid", last_good_path);
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to load last known good config: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Keep a timestamped copy of a config file that failed to parse, once per
+/// distinct content so restart loops do not pile up copies
+fn backup_corrupt(config_path: &Path) {
+    let Ok(contents) = fs::read(config_path) else {
+        return;
+    };
+    
+    let file_name = config_path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}.corrupt-", file_name);
+    
+    let already_saved = config_path.parent()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+        .any(|entry| fs::read(entry.path()).ok().as_deref() == Some(contents.as_slice()));
+    
+    if already_saved {
+        return;
+    }
+    
+    let timestamp = chrono::Local::now().format("%Y%m%dT%H%M%S");
+    let backup_path = config_path.with_file_name(format!("{}{}", prefix, timestamp));
+    
+    match utils::write_atomic(&backup_path, &contents) {
+        Ok(()) => log::warn!("Saved a copy of the unreadable config to {:?}", backup_path),
+        Err(e) => log::error!("Failed to back up the unreadable config: {:#}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn filters_events_by_kind() {
+        let target: WebhookTarget = serde_json::from_value(serde_json::json!({
+            "url": "https://example.com/hook",
+            "kinds": ["usb_*", "startup"],
+            "exclude_kinds": ["usb_disconnected"],
+        })).unwrap();
+        
+        let accepts = |kind: &str| target.accepts(&Event::new(crate::webhook::EventCategory::USB, kind, "Title", "Message"));
+        assert!(accepts("usb_connected"));
+        assert!(accepts("startup"));
+        assert!(!accepts("usb_disconnected"));
+        assert!(!accepts("startup_delayed"));
+        assert!(!accepts("idle"));
+        
+        assert!(WebhookTarget::discord("https://example.com/hook").accepts(&Event::new(crate::webhook::EventCategory::IDLE, "idle", "Title", "Message")));
+    }
+    
+    #[test]
+    fn names_the_key_of_invalid_destinations() {
+        let contents = r#"{ "destinations": { "alerts": { "url": "https://example.com/hook", "format": "slak" } } }"#;
+        let document = serde_json::from_str(contents).unwrap();
+        
+        let error = format::deserialize::<Config>(Path::new("config.json"), contents, &document).unwrap_err();
+        assert!(error.message.starts_with("destinations.alerts.format: unknown variant `slak`"), "{}", error.message);
+        assert!(error.message.contains("`slack`") && error.message.contains("`teams-adaptive`"), "{}", error.message);
+        assert_eq!(error.column - 1, contents.find("format").unwrap());
+        
+        let error = serde_json::from_str::<WebhookTarget>("42").unwrap_err();
+        assert!(error.to_string().contains("expected a webhook URL or a table with a `url`"), "{}", error);
+    }
+    
+    #[test]
+    fn reads_both_forms_of_destinations() {
+        let plain: WebhookTarget = serde_json::from_str(r#""https://example.com/hook""#).unwrap();
+        assert_eq!(plain.format, PayloadFormat::Discord);
+        assert_eq!(serde_json::to_string(&plain).unwrap(), r#""https://example.com/hook""#);
+        
+        let detailed: WebhookTarget = serde_json::from_str(r#"{ "url": "https://example.com/hook", "format": "slack" }"#).unwrap();
+        assert_eq!(detailed.format, PayloadFormat::Slack);
+        assert_eq!(serde_json::to_string(&detailed).unwrap(), r#"{"url":"https://example.com/hook","format":"slack"}"#);
+    }
+    
+    fn write_config(path: &Path, device_name: &str, ping_interval: u32) {
+        let config = serde_json::json!({
+            "config_version": migration::CURRENT_VERSION,
+            "device_name": device_name,
+            "destinations": { "alerts": "https://example.com/hook" },
+            "routes": [{ "match": {}, "to": "alerts" }],
+            "ping_interval": ping_interval,
+        });
+        fs::write(path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    }
+    
+    fn corrupt_copies(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains(".corrupt-"))
+            .collect()
+    }
+    
+    #[test]
+    fn falls_back_to_the_last_good_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        
+        write_config(&path, "good", 15);
+        assert_eq!(load_or_fall_back(&path).unwrap().device_name, "good");
+        assert_eq!(fs::read(last_good_path(&path)).unwrap(), fs::read(&path).unwrap());
+        
+        // Unreadable: backed up once and left in place
+        fs::write(&path, "{ \"device_name\": ").unwrap();
+        assert_eq!(load_or_fall_back(&path).unwrap().device_name, "good");
+        assert_eq!(load_or_fall_back(&path).unwrap().device_name, "good");
+        
+        let copies = corrupt_copies(dir.path());
+        assert_eq!(copies.len(), 1);
+        assert_eq!(fs::read_to_string(&copies[0]).unwrap(), "{ \"device_name\": ");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{ \"device_name\": ");
+        
+        // Readable but invalid: no backup needed
+        write_config(&path, "invalid", 0);
+        assert_eq!(load_or_fall_back(&path).unwrap().device_name, "good");
+        assert_eq!(corrupt_copies(dir.path()).len(), 1);
+        
+        write_config(&path, "fixed", 15);
+        assert_eq!(load_or_fall_back(&path).unwrap().device_name, "fixed");
+        assert!(fs::read_to_string(last_good_path(&path)).unwrap().contains("fixed"));
+    }
+    
+    #[test]
+    fn fails_without_a_last_good_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, "not json").unwrap();
+        
+        let error = load_or_fall_back(&path).unwrap_err();
+        assert!(error.downcast_ref::<ParseError>().is_some(), "{:#}", error);
+        assert_eq!(corrupt_copies(dir.path()).len(), 1);
+    }
+    
+    #[test]
+    fn save_writes_secret_references() {
+        std::env::set_var("RAA_TEST_SAVE_URL", "https://example.com/hooks/resolved-token");
+        std::env::set_var("RAA_TEST_SAVE_SECRET", "resolved-key");
+        
+        let mut config = default_config();
+        let target = config.destinations.get_mut("system").unwrap();
+        target.url = Secret::new("env:RAA_TEST_SAVE_URL");
+        target.secret = Some(Secret::new("env:RAA_TEST_SAVE_SECRET"));
+        
+        assert!(config.resolve_secrets().is_empty());
+        assert_eq!(config.destinations["system"].url.expose(), "https://example.com/hooks/resolved-token");
+        
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["config.json", "config.toml", "config.yaml"] {
+            let path = dir.path().join(name);
+            config.save_to(&path).unwrap();
+            
+            let contents = fs::read_to_string(&path).unwrap();
+            assert!(contents.contains("env:RAA_TEST_SAVE_URL"), "{}", contents);
+            assert!(contents.contains("env:RAA_TEST_SAVE_SECRET"), "{}", contents);
+            assert!(!contents.contains("resolved"), "{}", contents);
         }
     }
+    
+    #[cfg(unix)]
+    #[test]
+    fn save_keeps_the_config_private() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raa").join("config.toml");
+        default_config().save_to(&path).unwrap();
+        
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+    }
 }
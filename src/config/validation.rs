@@ -0,0 +1,385 @@
+use serde_json::Value;
+use std::fmt;
+use thiserror::Error;
+use super::{Config, EventMatch, HttpConfig, WebhookTarget};
+
+/// Keys accepted at the top level of the config file
+const CONFIG_KEYS: &[&str] = &["config_version", "device_name", "destinations", "routes", "ping_interval", "idle_threshold", "http"];
+const TARGET_KEYS: &[&str] = &["url", "format", "secret", "name", "kinds", "exclude_kinds", "min_severity"];
+const ROUTE_KEYS: &[&str] = &["match", "to"];
+const MATCH_KEYS: &[&str] = &["category", "kind", "min_severity", "fields"];
+const HTTP_KEYS: &[&str] = &["connect_timeout", "request_timeout", "proxy", "ca_certificates", "client_certificate"];
+const CLIENT_CERTIFICATE_KEYS: &[&str] = &["certificate", "key", "password"];
+
+/// Categories of the built-in triggers, for catching typos in route matches
+const CATEGORIES: &[&str] = &["system", "usb", "idle"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueLevel {
+    /// The agent cannot run correctly with this setting
+    Error,
+    /// Probably a mistake, but the agent can run
+    Warning,
+}
+
+/// A problem found in the config, located by its JSON path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub level: IssueLevel,
+    /// Location in the file, e.g. `routes[1].match.kind`
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    pub(super) fn error(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { level: IssueLevel::Error, path: path.into(), message: message.into() }
+    }
+    
+    pub(super) fn warning(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { level: IssueLevel::Warning, path: path.into(), message: message.into() }
+    }
+    
+    pub fn is_error(&self) -> bool {
+        self.level == IssueLevel::Error
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            IssueLevel::Error => "error",
+            IssueLevel::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", level, self.path, self.message)
+    }
+}
+
+/// The config has errors that keep the agent from running
+#[derive(Debug, Error)]
+#[error("Invalid config:\n{}", format_issues(.issues))]
+pub struct ValidationError {
+    pub issues: Vec<ConfigIssue>,
+}
+
+fn format_issues(issues: &[ConfigIssue]) -> String {
+    issues.iter()
+        .map(|issue| format!("  {}", issue))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Log the warnings among `issues` and fail if there are any errors
+pub fn ensure_valid(issues: Vec<ConfigIssue>) -> Result<(), ValidationError> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = issues.into_iter().partition(ConfigIssue::is_error);
+    
+    for warning in &warnings {
+        log::warn!("Config {}", warning);
+    }
+    
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { issues: errors })
+    }
+}
+
+/// Check the settings of a parsed config
+pub fn validate(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    
+    if config.device_name.trim().is_empty() {
+        issues.push(ConfigIssue::error("device_name", "must not be empty"));
+    }
+    
+    if config.ping_interval == 0 {
+        issues.push(ConfigIssue::error("ping_interval", "must be at least 1 minute"));
+    } else if config.ping_interval > 59 {
+        issues.push(ConfigIssue::warning("ping_interval", "heartbeats are sent at most once an hour with intervals above 59 minutes"));
+    }
+    
+    if config.idle_threshold == 0 {
+        issues.push(ConfigIssue::error("idle_threshold", "must be at least 1 minute"));
+    }
+    
+    for (name, target) in &config.destinations {
+        validate_target(&mut issues, &format!("destinations.{}", name), target);
+    }
+    
+    for (index, route) in config.routes.iter().enumerate() {
+        let path = format!("routes[{}]", index);
+        
+        if route.to.is_empty() {
+            issues.push(ConfigIssue::error(format!("{}.to", path), "must name at least one destination"));
+        }
+        
+        for name in &route.to {
+            if !config.destinations.contains_key(name) {
+                issues.push(ConfigIssue::error(format!("{}.to", path), format!("unknown destination '{}'", name)));
+            }
+        }
+        
+        validate_match(&mut issues, &format!("{}.match", path), &route.matcher);
+    }
+    
+    if config.routes.is_empty() {
+        issues.push(ConfigIssue::warning("routes", "no routes configured, events will not be sent anywhere"));
+    }
+    
+    validate_http(&mut issues, &config.http);
+    
+    issues
+}
+
+fn validate_target(issues: &mut Vec<ConfigIssue>, path: &str, target: &WebhookTarget) {
+    match reqwest::Url::parse(&target.url) {
+        // Secret references that failed to resolve are reported already
+        _ if !target.url.is_resolved() => {}
+        Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+            issues.push(ConfigIssue::error(format!("{}.url", path), format!("unsupported scheme '{}', expected http or https", url.scheme())));
+        }
+        Ok(url) if is_placeholder(&url) => {
+            issues.push(ConfigIssue::error(format!("{}.url", path), "placeholder Discord webhook URL, replace it with a real webhook"));
+        }
+        Ok(_) => {}
+        Err(e) => issues.push(ConfigIssue::error(format!("{}.url", path), format!("invalid URL: {}", e))),
+    }
+    
+    if target.secret.as_ref().is_some_and(|secret| secret.raw().is_empty()) {
+        issues.push(ConfigIssue::error(format!("{}.secret", path), "must not be empty"));
+    }
+    
+    for (key, patterns) in [("kinds", &target.kinds), ("exclude_kinds", &target.exclude_kinds)] {
+        for pattern in patterns {
+            check_pattern(issues, &format!("{}.{}", path, key), pattern);
+        }
+    }
+}
+
+/// Discord webhook URLs end in `/api/webhooks/<id>/<token>`; anything
+/// shorter is a leftover from the default config
+fn is_placeholder(url: &reqwest::Url) -> bool {
+    let is_discord = matches!(url.host_str(), Some("discord.com" | "discordapp.com" | "ptb.discord.com" | "canary.discord.com"));
+    let segments = url.path_segments().map(|segments| segments.filter(|s| !s.is_empty()).count()).unwrap_or(0);
+    
+    is_discord && url.path().starts_with("/api/webhooks") && segments < 4
+}
+
+fn validate_match(issues: &mut Vec<ConfigIssue>, path: &str, matcher: &EventMatch) {
+    for category in &matcher.category {
+        check_pattern(issues, &format!("{}.category", path), category);
+        
+        let known = match category.strip_suffix('*') {
+            Some(prefix) => CATEGORIES.iter().any(|c| c.starts_with(prefix)),
+            None => CATEGORIES.contains(&category.as_str()),
+        };
+        if !known {
+            issues.push(ConfigIssue::warning(format!("{}.category", path), format!("'{}' matches none of the built-in event categories (system, usb or idle)", category)));
+        }
+    }
+    
+    for kind in &matcher.kind {
+        check_pattern(issues, &format!("{}.kind", path), kind);
+    }
+    
+    for (name, pattern) in &matcher.fields {
+        check_pattern(issues, &format!("{}.fields.{}", path, name), pattern);
+    }
+}
+
+/// `*` is only a wildcard at the end of a pattern
+fn check_pattern(issues: &mut Vec<ConfigIssue>, path: &str, pattern: &str) {
+    if pattern.trim_end_matches('*').contains('*') {
+        issues.push(ConfigIssue::warning(path, format!("'{}' only supports `*` at the end, other `*` match literally", pattern)));
+    }
+}
+
+fn validate_http(issues: &mut Vec<ConfigIssue>, http: &HttpConfig) {
+    if http.connect_timeout == 0 {
+        issues.push(ConfigIssue::error("http.connect_timeout", "must be at least 1 second"));
+    }
+    
+    if http.request_timeout == 0 {
+        issues.push(ConfigIssue::error("http.request_timeout", "must be at least 1 second"));
+    }
+    
+    if let Some(proxy) = http.proxy.as_ref().filter(|proxy| proxy.is_resolved()) {
+        match reqwest::Url::parse(proxy) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") => {
+                issues.push(ConfigIssue::error("http.proxy", format!("unsupported proxy scheme '{}'", url.scheme())));
+            }
+            Ok(_) => {}
+            Err(e) => issues.push(ConfigIssue::error("http.proxy", format!("invalid URL: {}", e))),
+        }
+    }
+    
+    for (index, path) in http.ca_certificates.iter().enumerate() {
+        if !path.is_file() {
+            issues.push(ConfigIssue::error(format!("http.ca_certificates[{}]", index), format!("file {:?} not found", path)));
+        }
+    }
+    
+    if let Some(client_certificate) = &http.client_certificate {
+        let files = [("certificate", Some(&client_certificate.certificate)), ("key", client_certificate.key.as_ref())];
+        
+        for (key, path) in files {
+            if let Some(path) = path.filter(|path| !path.is_file()) {
+                issues.push(ConfigIssue::error(format!("http.client_certificate.{}", key), format!("file {:?} not found", path)));
+            }
+        }
+    }
+}
+
+/// Keys in the raw config file that no setting reads, usually typos
+pub fn unknown_keys(raw: &Value) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    
+    let Some(object) = raw.as_object() else {
+        return issues;
+    };
+    check_keys(&mut issues, "", raw, CONFIG_KEYS);
+    
+    if let Some(destinations) = object.get("destinations").and_then(Value::as_object) {
+        for (name, target) in destinations {
+            check_keys(&mut issues, &format!("destinations.{}", name), target, TARGET_KEYS);
+        }
+    }
+    
+    if let Some(routes) = object.get("routes").and_then(Value::as_array) {
+        for (index, route) in routes.iter().enumerate() {
+            let path = format!("routes[{}]", index);
+            check_keys(&mut issues, &path, route, ROUTE_KEYS);
+            
+            if let Some(matcher) = route.get("match") {
+                check_keys(&mut issues, &format!("{}.match", path), matcher, MATCH_KEYS);
+            }
+        }
+    }
+    
+    if let Some(http) = object.get("http") {
+        check_keys(&mut issues, "http", http, HTTP_KEYS);
+        
+        if let Some(client_certificate) = http.get("client_certificate") {
+            check_keys(&mut issues, "http.client_certificate", client_certificate, CLIENT_CERTIFICATE_KEYS);
+        }
+    }
+    
+    issues
+}
+
+fn check_keys(issues: &mut Vec<ConfigIssue>, path: &str, value: &Value, known: &[&str]) {
+    let Some(object) = value.as_object() else {
+        return;
+    };
+    
+    for key in object.keys().filter(|key| !known.contains(&key.as_str())) {
+        let key_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        issues.push(ConfigIssue::warning(key_path, format!("unknown key, expected one of: {}", known.join(", "))));
+    }
+}
+
+/// Type of the value expected at a key, for reading overrides given as text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Number,
+    List,
+    Table,
+}
+
+/// Type of the value at a dotted key such as `http.proxy`, or `None` for
+/// keys the config does not have
+pub fn value_type(key: &str) -> Option<ValueType> {
+    let parts: Vec<&str> = key.split('.').collect();
+    
+    match parts.as_slice() {
+        ["config_version" | "ping_interval" | "idle_threshold"] => Some(ValueType::Number),
+        ["device_name"] => Some(ValueType::String),
+        // A destination is a table, or its URL when read as a string
+        ["destinations"] | ["destinations", _] => Some(ValueType::Table),
+        ["destinations", _, "kinds" | "exclude_kinds"] => Some(ValueType::List),
+        ["destinations", _, key] if TARGET_KEYS.contains(key) => Some(ValueType::String),
+        ["routes"] => Some(ValueType::List),
+        ["http"] | ["http", "client_certificate"] => Some(ValueType::Table),
+        ["http", "connect_timeout" | "request_timeout"] => Some(ValueType::Number),
+        ["http", "ca_certificates"] => Some(ValueType::List),
+        ["http", "proxy"] => Some(ValueType::String),
+        ["http", "client_certificate", key] if CLIENT_CERTIFICATE_KEYS.contains(key) => Some(ValueType::String),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn config(value: Value) -> Config {
+        serde_json::from_value(value).unwrap()
+    }
+    
+    fn issue_at<'a>(issues: &'a [ConfigIssue], path: &str) -> Option<&'a ConfigIssue> {
+        issues.iter().find(|issue| issue.path == path)
+    }
+    
+    #[test]
+    fn rejects_placeholder_urls() {
+        let issues = validate(&config(json!({
+            "destinations": {
+                "placeholder": "https://discord.com/api/webhooks/system",
+                "real": "https://discord.com/api/webhooks/123/token",
+                "other": "https://example.com/api/webhooks/system",
+                "ftp": "ftp://example.com/hook",
+            },
+            "routes": [{ "match": {}, "to": ["placeholder", "real", "other", "ftp"] }],
+        })));
+        
+        let placeholder = issue_at(&issues, "destinations.placeholder.url").unwrap();
+        assert!(placeholder.is_error() && placeholder.message.contains("placeholder"), "{}", placeholder);
+        assert!(issue_at(&issues, "destinations.real.url").is_none());
+        assert!(issue_at(&issues, "destinations.other.url").is_none());
+        assert!(issue_at(&issues, "destinations.ftp.url").unwrap().message.contains("unsupported scheme 'ftp'"));
+    }
+    
+    #[test]
+    fn checks_the_ping_interval() {
+        let zero = validate(&config(json!({ "ping_interval": 0 })));
+        let issue = issue_at(&zero, "ping_interval").unwrap();
+        assert!(issue.is_error(), "{}", issue);
+        assert_eq!(issue.to_string(), "error: ping_interval: must be at least 1 minute");
+        
+        let hourly = validate(&config(json!({ "ping_interval": 90 })));
+        assert!(!issue_at(&hourly, "ping_interval").unwrap().is_error());
+        
+        assert!(issue_at(&validate(&config(json!({ "ping_interval": 15 }))), "ping_interval").is_none());
+    }
+    
+    #[test]
+    fn reports_unknown_keys_with_their_path() {
+        let issues = unknown_keys(&json!({
+            "device_name": "office-pc",
+            "ping_intervall": 5,
+            "destinations": {
+                "alerts": { "url": "https://example.com/hook", "fromat": "slack" },
+                "plain": "https://example.com/hook",
+            },
+            "routes": [{ "match": { "severity": "critical" }, "to": "alerts" }],
+            "http": { "proxy": "http://proxy:3128", "client_certificate": { "certificate": "c.pem", "pass": "x" } },
+        }));
+        
+        let paths: Vec<_> = issues.iter().map(|issue| issue.path.as_str()).collect();
+        assert_eq!(paths, ["ping_intervall", "destinations.alerts.fromat", "routes[0].match.severity", "http.client_certificate.pass"]);
+        assert!(issues.iter().all(|issue| !issue.is_error()));
+        assert!(issues[1].message.contains("expected one of: url, format"), "{}", issues[1]);
+    }
+    
+    #[test]
+    fn fails_only_on_errors() {
+        let warning = ConfigIssue::warning("routes", "no routes");
+        assert!(ensure_valid(vec![warning.clone()]).is_ok());
+        
+        let error = ensure_valid(vec![warning, ConfigIssue::error("device_name", "must not be empty")]).unwrap_err();
+        assert_eq!(error.issues.len(), 1);
+        assert_eq!(error.to_string(), "Invalid config:\n  error: device_name: must not be empty");
+    }
+}
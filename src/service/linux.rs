@@ -0,0 +1,289 @@
+use anyhow::{Result, Context};
+use std::path::PathBuf;
+use std::process::Command;
+use super::BackgroundService;
+
+/// Which systemd instance manages the service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceScope {
+    /// Per-user unit managed with `systemctl --user`
+    User,
+    /// System-wide unit managed by the system instance (requires root)
+    System,
+}
+
+impl ServiceScope {
+    /// System scope when running as root, user scope otherwise
+    pub fn detect() -> Self {
+        use std::os::unix::fs::MetadataExt;
+        
+        // /proc/self is owned by the effective user of the current process
+        match std::fs::metadata("/proc/self") {
+            Ok(metadata) if metadata.uid() == 0 => ServiceScope::System,
+            _ => ServiceScope::User,
+        }
+    }
+    
+    fn default_unit_dir(&self) -> Result<PathBuf> {
+        match self {
+            ServiceScope::User => Ok(dirs::config_dir()
+                .context("Could not find config directory")?
+                .join("systemd/user")),
+            ServiceScope::System => Ok(PathBuf::from("/etc/systemd/system")),
+        }
+    }
+    
+    fn wanted_by(&self) -> &'static str {
+        match self {
+            ServiceScope::User => "default.target",
+            ServiceScope::System => "multi-user.target",
+        }
+    }
+}
+
+pub struct LinuxService {
+    name: String,
+    display_name: String,
+    description: String,
+    executable_path: PathBuf,
+    scope: ServiceScope,
+    unit_dir: PathBuf,
+}
+
+impl LinuxService {
+    pub fn new(name: &str, display_name: &str, description: &str, executable_path: PathBuf) -> Result<Self> {
+        Self::with_scope(name, display_name, description, executable_path, ServiceScope::detect())
+    }
+    
+    pub fn with_scope(name: &str, display_name: &str, description: &str, executable_path: PathBuf, scope: ServiceScope) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            description: description.to_string(),
+            executable_path,
+            scope,
+            unit_dir: scope.default_unit_dir()?,
+        })
+    }
+    
+    /// Write unit files into `unit_dir` instead of the systemd search path
+    pub fn with_unit_dir(mut self, unit_dir: impl Into<PathBuf>) -> Self {
+        self.unit_dir = unit_dir.into();
+        self
+    }
+    
+    pub fn scope(&self) -> ServiceScope {
+        self.scope
+    }
+    
+    pub fn unit_name(&self) -> String {
+        format!("{}.service", self.name)
+    }
+    
+    pub fn unit_path(&self) -> PathBuf {
+        self.unit_dir.join(self.unit_name())
+    }
+    
+    /// Render the contents of the systemd unit file
+    pub fn render_unit(&self) -> Result<String> {
+        let executable = self.executable_path.to_str()
+            .context("Invalid executable path")?;
+        
+        Ok(format!(
+            r#"[Unit]
+Description={} - {}
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+ExecStart={} run
+Restart=on-failure
+RestartSec=10
+
+[Install]
+WantedBy={}
+"#,
+            self.display_name,
+            self.description,
+            quote_exec_arg(executable),
+            self.scope.wanted_by()
+        ))
+    }
+    
+    /// Write the unit file to `unit_path` without touching systemd
+    pub fn write_unit(&self) -> Result<PathBuf> {
+        let unit_path = self.unit_path();
+        
+        if let Some(parent) = unit_path.parent() {
+            std::fs::create_dir_all(parent)
+                .context("Failed to create systemd unit directory")?;
+        }
+        
+        std::fs::write(&unit_path, self.render_unit()?)
+            .with_context(|| format!("Failed to write unit file to {:?}", unit_path))?;
+        
+        Ok(unit_path)
+    }
+    
+    fn systemctl(&self) -> Command {
+        let mut command = Command::new("systemctl");
+        if self.scope == ServiceScope::User {
+            command.arg("--user");
+        }
+        command
+    }
+    
+    fn run_systemctl(&self, args: &[&str]) -> Result<()> {
+        let status = self.systemctl()
+            .args(args)
+            .status()
+            .context("Failed to execute systemctl")?;
+        
+        if !status.success() {
+            return Err(anyhow::anyhow!("systemctl {} failed for {}", args.join(" "), self.unit_name()));
+        }
+        
+        Ok(())
+    }
+}
+
+impl BackgroundService for LinuxService {
+    fn install(&self) -> Result<()> {
+        let unit_path = self.write_unit()?;
+        
+        self.run_systemctl(&["daemon-reload"])?;
+        self.run_systemctl(&["enable", "--now", &self.unit_name()])?;
+        
+        log::info!("Installed systemd service {} at {}", self.name, unit_path.display());
+        Ok(())
+    }
+    
+    fn uninstall(&self) -> Result<()> {
+        let unit_path = self.unit_path();
+        
+        if unit_path.exists() {
+            if let Err(e) = self.run_systemctl(&["disable", "--now", &self.unit_name()]) {
+                log::warn!("Failed to disable service: {}", e);
+            }
+            
+            std::fs::remove_file(&unit_path)
+                .context("Failed to remove unit file")?;
+            self.run_systemctl(&["daemon-reload"])?;
+        }
+        
+        log::info!("Uninstalled systemd service: {}", self.name);
+        Ok(())
+    }
+    
+    fn start(&self) -> Result<()> {
+        self.run_systemctl(&["start", &self.unit_name()])?;
+        
+        log::info!("Started systemd service: {}", self.name);
+        Ok(())
+    }
+    
+    fn stop(&self) -> Result<()> {
+        self.run_systemctl(&["stop", &self.unit_name()])?;
+        
+        log::info!("Stopped systemd service: {}", self.name);
+        Ok(())
+    }
+    
+    fn is_installed(&self) -> Result<bool> {
+        Ok(self.unit_path().exists())
+    }
+    
+    fn is_running(&self) -> Result<bool> {
+        let status = self.systemctl()
+            .args(["is-active", "--quiet", &self.unit_name()])
+            .status()
+            .context("Failed to execute systemctl")?;
+        
+        Ok(status.success())
+    }
+}
+
+/// Quote an ExecStart argument if systemd would otherwise split it, and
+/// escape `%` specifiers and `$` variable references
+fn quote_exec_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    
+    if arg.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn service(executable: &str, scope: ServiceScope, unit_dir: &std::path::Path) -> LinuxService {
+        LinuxService::with_scope("raa", "RAA", "Remote Access Agent", PathBuf::from(executable), scope)
+            .unwrap()
+            .with_unit_dir(unit_dir)
+    }
+    
+    fn exec_start(unit: &str) -> &str {
+        unit.lines()
+            .find_map(|line| line.strip_prefix("ExecStart="))
+            .unwrap()
+    }
+    
+    #[test]
+    fn writes_user_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service("/usr/bin/raa", ServiceScope::User, dir.path());
+        
+        let path = service.write_unit().unwrap();
+        assert_eq!(path, dir.path().join("raa.service"));
+        
+        let unit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(exec_start(&unit), "/usr/bin/raa run");
+        assert!(unit.contains("\nWantedBy=default.target\n"));
+        assert!(service.is_installed().unwrap());
+    }
+    
+    #[test]
+    fn writes_system_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit_dir = dir.path().join("systemd/system");
+        let service = service("/opt/raa/raa", ServiceScope::System, &unit_dir);
+        
+        let path = service.write_unit().unwrap();
+        assert_eq!(path, unit_dir.join("raa.service"));
+        
+        let unit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(exec_start(&unit), "/opt/raa/raa run");
+        assert!(unit.contains("\nWantedBy=multi-user.target\n"));
+    }
+    
+    #[test]
+    fn default_unit_dirs() {
+        assert_eq!(ServiceScope::System.default_unit_dir().unwrap(), PathBuf::from("/etc/systemd/system"));
+        if let Some(config_dir) = dirs::config_dir() {
+            assert_eq!(ServiceScope::User.default_unit_dir().unwrap(), config_dir.join("systemd/user"));
+        }
+    }
+    
+    #[test]
+    fn quotes_exec_start() {
+        let dir = tempfile::tempdir().unwrap();
+        
+        let cases = [
+            ("/home/me/My Apps/raa", r#""/home/me/My Apps/raa" run"#),
+            (r#"/opt/say "hi"/raa"#, r#""/opt/say \"hi\"/raa" run"#),
+            (r"/opt/back\slash/raa", r#""/opt/back\\slash/raa" run"#),
+            ("/opt/100%/raa", "/opt/100%%/raa run"),
+            ("/opt/$HOME/raa", "/opt/$$HOME/raa run"),
+            ("/opt/50% off/raa", r#""/opt/50%% off/raa" run"#),
+        ];
+        
+        for (executable, expected) in cases {
+            let unit = service(executable, ServiceScope::User, dir.path()).render_unit().unwrap();
+            assert_eq!(exec_start(&unit), expected, "{}", executable);
+        }
+    }
+}
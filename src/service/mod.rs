@@ -1,8 +1,11 @@
 use anyhow::Result;
 use std::path::PathBuf;
 
+#[cfg(target_os = "macos")]
 pub mod macos;
 pub mod windows;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub mod linux;
 
 /// Common trait for background services across platforms
 pub trait BackgroundService {
@@ -15,20 +18,20 @@ pub trait BackgroundService {
 }
 
 /// Get the platform-specific service implementation
-pub fn get_service(name: &str, display_name: &str, description: &str, executable_path: PathBuf) -> Box<dyn BackgroundService> {
+pub fn get_service(name: &str, display_name: &str, description: &str, executable_path: PathBuf) -> Result<Box<dyn BackgroundService>> {
     #[cfg(target_os = "windows")]
     {
-        Box::new(windows::WindowsService::new(name, display_name, description, executable_path))
+        Ok(Box::new(windows::WindowsService::new(name, display_name, description, executable_path)?))
     }
     
     #[cfg(target_os = "macos")]
     {
-        Box::new(macos::MacOsService::new(name, display_name, description, executable_path))
+        Ok(Box::new(macos::MacOsService::new(name, display_name, description, executable_path)))
     }
     
     #[cfg(not(any(target_os = "windows", target_os = "macos")))]
     {
         // Fallback for Linux or other platforms
-        Box::new(linux::LinuxService::new(name, display_name, description, executable_path))
+        Ok(Box::new(linux::LinuxService::new(name, display_name, description, executable_path)?))
     }
 }
@@ -0,0 +1,479 @@
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Maximum length of a service name or display name accepted by the SCM
+const MAX_NAME_LENGTH: usize = 256;
+
+/// Action taken by the service control manager after the service fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// Restart the service after the given delay
+    Restart(Duration),
+    /// Leave the service stopped
+    None,
+}
+
+/// Platform-neutral description of the Windows service registration.
+///
+/// Holds everything `WindowsService` passes to the service control manager,
+/// so it can be built, rendered and validated on any platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDescriptor {
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    pub executable_path: PathBuf,
+    pub arguments: Vec<String>,
+    pub recovery_actions: Vec<RecoveryAction>,
+    pub failure_reset_period: Duration,
+}
+
+impl ServiceDescriptor {
+    pub fn new(name: &str, display_name: &str, description: &str, executable_path: PathBuf) -> Self {
+        Self {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            description: description.to_string(),
+            executable_path,
+            // Hands control to the service dispatcher instead of the console runtime
+            arguments: vec!["service".to_string()],
+            recovery_actions: vec![
+                RecoveryAction::Restart(Duration::from_secs(10)),
+                RecoveryAction::Restart(Duration::from_secs(30)),
+                RecoveryAction::Restart(Duration::from_secs(60)),
+            ],
+            failure_reset_period: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+    
+    /// Render the command line the SCM stores as the service's image path
+    pub fn command_line(&self) -> String {
+        let executable = self.executable_path.to_string_lossy();
+        
+        std::iter::once(quote_arg(&executable))
+            .chain(self.arguments.iter().map(|arg| quote_arg(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+    
+    /// Check the descriptor against the limits of the service control manager
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        
+        if self.name.is_empty() {
+            problems.push("service name must not be empty".to_string());
+        } else if self.name.chars().count() > MAX_NAME_LENGTH {
+            problems.push(format!("service name must be at most {} characters", MAX_NAME_LENGTH));
+        }
+        if self.name.contains(['/', '\\']) {
+            problems.push("service name must not contain '/' or '\\'".to_string());
+        }
+        
+        if self.display_name.is_empty() {
+            problems.push("display name must not be empty".to_string());
+        } else if self.display_name.chars().count() > MAX_NAME_LENGTH {
+            problems.push(format!("display name must be at most {} characters", MAX_NAME_LENGTH));
+        }
+        
+        let executable = self.executable_path.to_string_lossy();
+        if !is_absolute_windows_path(&executable) {
+            problems.push(format!("executable path '{}' must be an absolute Windows path", executable));
+        }
+        
+        let has_nul = [&self.name, &self.display_name, &self.description]
+            .into_iter()
+            .chain(self.arguments.iter())
+            .any(|s| s.contains('\0'));
+        if has_nul || executable.contains('\0') {
+            problems.push("service strings must not contain NUL characters".to_string());
+        }
+        
+        for action in &self.recovery_actions {
+            if let RecoveryAction::Restart(delay) = action {
+                if u32::try_from(delay.as_millis()).is_err() {
+                    problems.push(format!("restart delay {:?} is too long", delay));
+                }
+            }
+        }
+        if u32::try_from(self.failure_reset_period.as_secs()).is_err() {
+            problems.push(format!("failure reset period {:?} is too long", self.failure_reset_period));
+        }
+        
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Invalid Windows service descriptor: {}", problems.join("; ")))
+        }
+    }
+}
+
+/// Quote an argument following the `CommandLineToArgvW` rules
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Escape the preceding backslashes and the quote itself
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    
+    // Backslashes before the closing quote must be doubled
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+fn is_absolute_windows_path(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    
+    let drive_absolute = bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes[2] == b'\\' || bytes[2] == b'/');
+    
+    drive_absolute || path.starts_with("\\\\")
+}
+
+#[cfg(target_os = "windows")]
+pub use platform::{run_service_dispatcher, WindowsService};
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use anyhow::{Context, Result};
+    use std::ffi::{OsStr, OsString};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use windows_service::define_windows_service;
+    use windows_service::service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+        ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_dispatcher;
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use super::{RecoveryAction, ServiceDescriptor};
+    use crate::service::BackgroundService;
+    
+    const ERROR_SERVICE_DOES_NOT_EXIST: i32 = 1060;
+    
+    /// How long the SCM should wait for the agent to finish starting
+    const START_WAIT_HINT: Duration = Duration::from_secs(30);
+    
+    pub struct WindowsService {
+        descriptor: ServiceDescriptor,
+    }
+    
+    impl WindowsService {
+        /// Service reading its config from the machine-wide service config
+        /// directory, see `get_service_config_dir`
+        pub fn new(name: &str, display_name: &str, description: &str, executable_path: PathBuf) -> Result<Self> {
+            let mut descriptor = ServiceDescriptor::new(name, display_name, description, executable_path);
+            let config_dir = crate::config::get_service_config_dir()?;
+            descriptor.arguments.push(config_dir.to_string_lossy().into_owned());
+            
+            Ok(Self::from_descriptor(descriptor))
+        }
+        
+        pub fn from_descriptor(descriptor: ServiceDescriptor) -> Self {
+            Self { descriptor }
+        }
+        
+        pub fn descriptor(&self) -> &ServiceDescriptor {
+            &self.descriptor
+        }
+        
+        fn manager(access: ServiceManagerAccess) -> Result<ServiceManager> {
+            ServiceManager::local_computer(None::<&str>, access)
+                .context("Failed to connect to the service control manager")
+        }
+        
+        fn open(&self, access: ServiceAccess) -> Result<windows_service::service::Service> {
+            Self::manager(ServiceManagerAccess::CONNECT)?
+                .open_service(&self.descriptor.name, access)
+                .with_context(|| format!("Failed to open service {}", self.descriptor.name))
+        }
+        
+        fn failure_actions(&self) -> ServiceFailureActions {
+            let actions = self.descriptor.recovery_actions.iter()
+                .map(|action| match action {
+                    RecoveryAction::Restart(delay) => ServiceAction {
+                        action_type: ServiceActionType::Restart,
+                        delay: *delay,
+                    },
+                    RecoveryAction::None => ServiceAction {
+                        action_type: ServiceActionType::None,
+                        delay: Duration::default(),
+                    },
+                })
+                .collect();
+            
+            ServiceFailureActions {
+                reset_period: ServiceFailureResetPeriod::After(self.descriptor.failure_reset_period),
+                reboot_msg: None,
+                command: None,
+                actions: Some(actions),
+            }
+        }
+    }
+    
+    impl BackgroundService for WindowsService {
+        fn install(&self) -> Result<()> {
+            self.descriptor.validate()?;
+            
+            let manager = Self::manager(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+            
+            let info = ServiceInfo {
+                name: OsString::from(&self.descriptor.name),
+                display_name: OsString::from(&self.descriptor.display_name),
+                service_type: ServiceType::OWN_PROCESS,
+                start_type: ServiceStartType::AutoStart,
+                error_control: ServiceErrorControl::Normal,
+                executable_path: self.descriptor.executable_path.clone(),
+                launch_arguments: self.descriptor.arguments.iter().map(OsString::from).collect(),
+                dependencies: vec![],
+                account_name: None,
+                account_password: None,
+            };
+            
+            let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+                .context("Failed to create Windows service")?;
+            
+            service.set_description(&self.descriptor.description)
+                .context("Failed to set service description")?;
+            service.update_failure_actions(self.failure_actions())
+                .context("Failed to configure service recovery actions")?;
+            service.start(&[] as &[&OsStr])
+                .context("Failed to start Windows service")?;
+            
+            log::info!("Installed Windows service: {}", self.descriptor.name);
+            Ok(())
+        }
+        
+        fn uninstall(&self) -> Result<()> {
+            if self.is_installed()? {
+                let service = self.open(ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+                
+                if service.query_status()?.current_state != ServiceState::Stopped {
+                    if let Err(e) = service.stop() {
+                        log::warn!("Failed to stop service before uninstalling: {}", e);
+                    }
+                }
+                
+                service.delete().context("Failed to delete Windows service")?;
+            }
+            
+            log::info!("Uninstalled Windows service: {}", self.descriptor.name);
+            Ok(())
+        }
+        
+        fn start(&self) -> Result<()> {
+            self.open(ServiceAccess::START)?
+                .start(&[] as &[&OsStr])
+                .context("Failed to start Windows service")?;
+            
+            log::info!("Started Windows service: {}", self.descriptor.name);
+            Ok(())
+        }
+        
+        fn stop(&self) -> Result<()> {
+            self.open(ServiceAccess::STOP)?
+                .stop()
+                .context("Failed to stop Windows service")?;
+            
+            log::info!("Stopped Windows service: {}", self.descriptor.name);
+            Ok(())
+        }
+        
+        fn is_installed(&self) -> Result<bool> {
+            let manager = Self::manager(ServiceManagerAccess::CONNECT)?;
+            
+            match manager.open_service(&self.descriptor.name, ServiceAccess::QUERY_STATUS) {
+                Ok(_) => Ok(true),
+                Err(windows_service::Error::Winapi(e)) if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST) => Ok(false),
+                Err(e) => Err(e).context("Failed to query Windows service"),
+            }
+        }
+        
+        fn is_running(&self) -> Result<bool> {
+            let status = self.open(ServiceAccess::QUERY_STATUS)?.query_status()?;
+            Ok(status.current_state == ServiceState::Running)
+        }
+    }
+    
+    define_windows_service!(ffi_service_main, service_main);
+    
+    /// Hand the process over to the service control manager.
+    ///
+    /// Blocks until the service has stopped; only works when the process was
+    /// started by the SCM.
+    pub fn run_service_dispatcher(name: &str) -> Result<()> {
+        service_dispatcher::start(name, ffi_service_main)
+            .context("Failed to start the service dispatcher")
+    }
+    
+    fn service_main(arguments: Vec<OsString>) {
+        if let Err(e) = run_service(arguments) {
+            log::error!("Windows service failed: {}", e);
+        }
+    }
+    
+    fn run_service(arguments: Vec<OsString>) -> Result<()> {
+        // The SCM passes the service name as the first argument
+        let name = arguments.first().cloned().unwrap_or_default();
+        let stop_requested = Arc::new(tokio::sync::Notify::new());
+        
+        let handler_stop = Arc::clone(&stop_requested);
+        let status_handle = service_control_handler::register(&name, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                handler_stop.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+        
+        // Running is only reported once the config has loaded and the agent is up
+        status_handle.set_service_status(ServiceStatus {
+            wait_hint: START_WAIT_HINT,
+            ..status(ServiceState::StartPending, ServiceControlAccept::empty(), 0)
+        })?;
+        
+        let started = move || {
+            let running = status(ServiceState::Running, ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN, 0);
+            if let Err(e) = status_handle.set_service_status(running) {
+                log::error!("Failed to report the service as running: {}", e);
+            }
+        };
+        
+        let result = crate::config::ensure_config_exists().and_then(|config| {
+            let runtime = tokio::runtime::Runtime::new()
+                .context("Failed to start tokio runtime")?;
+            
+            runtime.block_on(crate::agent::run_until(config, started, async move {
+                stop_requested.notified().await;
+                Ok(())
+            }))
+        });
+        
+        let exit_code = if result.is_ok() { 0 } else { 1 };
+        status_handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty(), exit_code))?;
+        
+        result
+    }
+    
+    fn status(state: ServiceState, controls_accepted: ServiceControlAccept, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn descriptor() -> ServiceDescriptor {
+        ServiceDescriptor::new("raa", "RAA", "Remote Access Agent", PathBuf::from(r"C:\Program Files\RAA\raa.exe"))
+    }
+    
+    #[test]
+    fn quotes_arguments() {
+        let cases = [
+            ("plain", "plain"),
+            ("", r#""""#),
+            (r"C:\no\spaces\", r"C:\no\spaces\"),
+            ("two words", r#""two words""#),
+            ("tab\there", "\"tab\there\""),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            (r#"slash\"quote"#, r#""slash\\\"quote""#),
+            (r"C:\with space\", r#""C:\with space\\""#),
+            (r"C:\with space\\", r#""C:\with space\\\\""#),
+        ];
+        
+        for (arg, expected) in cases {
+            assert_eq!(quote_arg(arg), expected, "{:?}", arg);
+        }
+    }
+    
+    #[test]
+    fn renders_command_line() {
+        assert_eq!(descriptor().command_line(), r#""C:\Program Files\RAA\raa.exe" service"#);
+        
+        let mut descriptor = descriptor();
+        descriptor.executable_path = PathBuf::from(r"C:\RAA\raa.exe");
+        descriptor.arguments = vec!["service".to_string(), r"C:\config dir\".to_string()];
+        assert_eq!(descriptor.command_line(), r#"C:\RAA\raa.exe service "C:\config dir\\""#);
+    }
+    
+    #[test]
+    fn accepts_valid_descriptors() {
+        descriptor().validate().unwrap();
+        
+        let mut unc = descriptor();
+        unc.executable_path = PathBuf::from(r"\\server\share\raa.exe");
+        unc.validate().unwrap();
+        
+        let mut forward_slashes = descriptor();
+        forward_slashes.executable_path = PathBuf::from("C:/RAA/raa.exe");
+        forward_slashes.validate().unwrap();
+    }
+    
+    type Breakage = fn(&mut ServiceDescriptor);
+    
+    #[test]
+    fn rejects_invalid_descriptors() {
+        let invalid: [(&str, Breakage); 8] = [
+            ("service name must not be empty", |d| d.name.clear()),
+            ("service name must be at most", |d| d.name = "a".repeat(MAX_NAME_LENGTH + 1)),
+            ("must not contain '/'", |d| d.name = r"raa\agent".to_string()),
+            ("display name must not be empty", |d| d.display_name.clear()),
+            ("must be an absolute Windows path", |d| d.executable_path = PathBuf::from(r"RAA\raa.exe")),
+            ("must not contain NUL", |d| d.arguments.push("a\0b".to_string())),
+            ("restart delay", |d| d.recovery_actions = vec![RecoveryAction::Restart(Duration::from_secs(5_000_000))]),
+            ("failure reset period", |d| d.failure_reset_period = Duration::from_secs(u64::from(u32::MAX) + 1)),
+        ];
+        
+        for (problem, break_descriptor) in invalid {
+            let mut descriptor = descriptor();
+            break_descriptor(&mut descriptor);
+            
+            let error = descriptor.validate().unwrap_err().to_string();
+            assert!(error.contains(problem), "expected '{}' in '{}'", problem, error);
+        }
+    }
+    
+    #[test]
+    fn reports_every_problem() {
+        let mut descriptor = descriptor();
+        descriptor.name.clear();
+        descriptor.executable_path = PathBuf::from("raa.exe");
+        
+        let error = descriptor.validate().unwrap_err().to_string();
+        assert!(error.contains("service name must not be empty; executable path 'raa.exe'"), "{}", error);
+    }
+}
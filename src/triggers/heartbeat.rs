@@ -1,35 +1,74 @@
 use anyhow::Result;
+use tokio::sync::Mutex;
 use tokio_cron_scheduler::{JobScheduler, Job};
 use std::sync::Arc;
-use crate::webhook::{EventCategory, WebhookSender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::webhook::{Event, EventCategory, Notifier, Severity};
 use crate::triggers::system;
 
 pub struct HeartbeatScheduler {
-    webhook: WebhookSender,
-    interval_minutes: u64,
+    notifier: Arc<dyn Notifier>,
+    interval_minutes: AtomicU64,
+    scheduler: Mutex<Option<JobScheduler>>,
 }
 
 impl HeartbeatScheduler {
-    pub fn new(webhook: WebhookSender, interval_minutes: u64) -> Self {
+    pub fn new(notifier: Arc<dyn Notifier>, interval_minutes: u64) -> Self {
         Self {
-            webhook,
-            interval_minutes,
+            notifier,
+            interval_minutes: AtomicU64::new(interval_minutes),
+            scheduler: Mutex::new(None),
         }
     }
     
     pub async fn start(&self) -> Result<()> {
-        let webhook = self.webhook.clone();
+        let interval_minutes = self.interval_minutes.load(Ordering::Relaxed);
+        let scheduler = self.schedule(interval_minutes).await?;
+        
+        self.activate(scheduler, interval_minutes).await
+    }
+    
+    /// Build the schedule for a new interval without touching the running
+    /// one, so a reload can fail before anything has changed
+    pub async fn prepare_interval(&self, interval_minutes: u64) -> Result<IntervalChange> {
+        let running = self.scheduler.lock().await.is_some();
+        let changed = self.interval_minutes.load(Ordering::Relaxed) != interval_minutes;
+        
+        let scheduler = if running && changed {
+            Some(self.schedule(interval_minutes).await?)
+        } else {
+            None
+        };
+        
+        Ok(IntervalChange { interval_minutes, scheduler })
+    }
+    
+    /// Switch to a prepared interval, rescheduling the heartbeat if it is
+    /// running
+    pub async fn apply_interval(&self, change: IntervalChange) -> Result<()> {
+        if let Some(scheduler) = change.scheduler {
+            self.activate(scheduler, change.interval_minutes).await?;
+        }
+        self.interval_minutes.store(change.interval_minutes, Ordering::Relaxed);
+        
+        Ok(())
+    }
+    
+    /// A scheduler, not started yet, sending a heartbeat every
+    /// `interval_minutes`
+    async fn schedule(&self, interval_minutes: u64) -> Result<JobScheduler> {
+        let notifier = Arc::clone(&self.notifier);
         let scheduler = JobScheduler::new().await?;
         
         // Define cron expression for the interval
         // This is every N minutes
-        let cron_expr = format!("0 */{} * * * *", self.interval_minutes);
+        let cron_expr = format!("0 */{} * * * *", interval_minutes);
         
         // Create a job that will execute the heartbeat function
         let job = Job::new_async(cron_expr.as_str(), move |_, _| {
-            let webhook_clone = webhook.clone();
+            let notifier = Arc::clone(&notifier);
             Box::pin(async move {
-                send_heartbeat(&webhook_clone).unwrap_or_else(|e| {
+                send_heartbeat(notifier.as_ref()).await.unwrap_or_else(|e| {
                     log::error!("Failed to send heartbeat: {}", e);
                 });
             })
@@ -38,24 +77,51 @@ impl HeartbeatScheduler {
         // Add the job to the scheduler
         scheduler.add(job).await?;
         
-        // Start the scheduler
+        Ok(scheduler)
+    }
+    
+    /// Start `scheduler` and shut down the one it replaces
+    async fn activate(&self, scheduler: JobScheduler, interval_minutes: u64) -> Result<()> {
         scheduler.start().await?;
         
-        log::info!("Heartbeat scheduled to run every {} minutes", self.interval_minutes);
+        log::info!("Heartbeat scheduled to run every {} minutes", interval_minutes);
+        
+        // Keep the scheduler so it can be shut down later
+        if let Some(mut previous) = self.scheduler.lock().await.replace(scheduler) {
+            // The new schedule is already running, so this is not worth failing over
+            if let Err(e) = previous.shutdown().await {
+                log::warn!("Failed to shut down the previous heartbeat schedule: {}", e);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    pub async fn stop(&self) -> Result<()> {
+        if let Some(mut scheduler) = self.scheduler.lock().await.take() {
+            scheduler.shutdown().await?;
+            log::info!("Heartbeat stopped");
+        }
         
-        // The scheduler will run on its own thread, so we can just return
         Ok(())
     }
 }
 
-fn send_heartbeat(webhook: &WebhookSender) -> Result<()> {
-    // Get system information for the heartbeat
-    let system_info = system::get_system_info();
+/// A heartbeat interval checked by `HeartbeatScheduler::prepare_interval`
+pub struct IntervalChange {
+    interval_minutes: u64,
+    /// Schedule replacing the running one, when the interval changed
+    scheduler: Option<JobScheduler>,
+}
+
+async fn send_heartbeat(notifier: &dyn Notifier) -> Result<()> {
+    // Get system information for the heartbeat; sysinfo blocks while refreshing
+    let system_info = tokio::task::spawn_blocking(system::get_system_info).await?;
     
-    webhook.send(
-        EventCategory::System,
+    notifier.notify(&Event::new(
+        EventCategory::SYSTEM,
+        "heartbeat",
         "Heartbeat",
         "Regular system heartbeat check-in",
-        system_info
-    )
+    ).with_fields(system_info).with_severity(Severity::Info)).await
 }
@@ -2,21 +2,25 @@ use anyhow::Result;
 use std::time::{Duration, Instant};
 use std::thread;
 use std::sync::{Arc, Mutex};
-use crate::webhook::{EventCategory, WebhookSender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::webhook::{BlockingNotifier, Event, EventCategory, Notifier, Severity};
+use super::session;
 
 pub struct IdleMonitor {
-    webhook: WebhookSender,
-    idle_threshold: Duration,
+    notifier: Arc<dyn Notifier>,
+    /// Seconds without activity before the system counts as idle; shared
+    /// with the monitoring thread so it can change while running
+    idle_threshold: Arc<AtomicU64>,
     check_interval: Duration,
     last_activity: Arc<Mutex<Instant>>,
     running: Arc<Mutex<bool>>,
 }
 
 impl IdleMonitor {
-    pub fn new(webhook: WebhookSender, idle_minutes: u64) -> Self {
+    pub fn new(notifier: Arc<dyn Notifier>, idle_minutes: u64) -> Self {
         Self {
-            webhook,
-            idle_threshold: Duration::from_secs(idle_minutes * 60),
+            notifier,
+            idle_threshold: Arc::new(AtomicU64::new(idle_minutes * 60)),
             check_interval: Duration::from_secs(60), // Check every minute
             last_activity: Arc::new(Mutex::new(Instant::now())),
             running: Arc::new(Mutex::new(false)),
@@ -26,8 +30,8 @@ impl IdleMonitor {
     pub fn start_monitoring(&self) -> Result<()> {
         let last_activity = Arc::clone(&self.last_activity);
         let running = Arc::clone(&self.running);
-        let webhook = self.webhook.clone();
-        let idle_threshold = self.idle_threshold;
+        let notifier = BlockingNotifier::new(Arc::clone(&self.notifier))?;
+        let idle_threshold = Arc::clone(&self.idle_threshold);
         let check_interval = self.check_interval;
         
         // Set running to true
@@ -45,6 +49,7 @@ impl IdleMonitor {
                     let last = last_activity.lock().unwrap();
                     last.elapsed()
                 };
+                let idle_threshold = Duration::from_secs(idle_threshold.load(Ordering::Relaxed));
                 
                 // If we've crossed the idle threshold and weren't previously idle
                 if idle_time >= idle_threshold && !was_idle {
@@ -52,7 +57,7 @@ impl IdleMonitor {
                     
                     // Send idle notification
                     let minutes = idle_time.as_secs() / 60;
-                    let _ = send_idle_notification(&webhook, minutes);
+                    let _ = send_idle_notification(&notifier, minutes);
                     
                     log::info!("System idle for {} minutes", minutes);
                 }
@@ -61,7 +66,7 @@ impl IdleMonitor {
                     was_idle = false;
                     
                     // Send active notification
-                    let _ = send_active_notification(&webhook, idle_time.as_secs() / 60);
+                    let _ = send_active_notification(&notifier, idle_time.as_secs() / 60);
                     
                     log::info!("System returned from idle state");
                 }
@@ -71,6 +76,11 @@ impl IdleMonitor {
         Ok(())
     }
     
+    /// Change the idle threshold without losing the current idle state
+    pub fn set_threshold(&self, idle_minutes: u64) {
+        self.idle_threshold.store(idle_minutes * 60, Ordering::Relaxed);
+    }
+    
     pub fn update_activity(&self) {
         let mut last_activity = self.last_activity.lock().unwrap();
         *last_activity = Instant::now();
@@ -82,28 +92,31 @@ impl IdleMonitor {
     }
 }
 
-fn send_idle_notification(webhook: &WebhookSender, idle_minutes: u64) -> Result<()> {
+fn send_idle_notification(notifier: &BlockingNotifier, idle_minutes: u64) -> Result<()> {
     let additional_fields = vec![
         ("Idle Time".to_string(), format!("{} minutes", idle_minutes)),
     ];
     
-    webhook.send(
-        EventCategory::Idle,
+    notifier.notify(&Event::new(
+        EventCategory::IDLE,
+        "idle",
         "System Idle",
         &format!("System has been idle for {} minutes", idle_minutes),
-        additional_fields
-    )
+    ).with_fields(additional_fields).with_severity(Severity::Info))
 }
 
-fn send_active_notification(webhook: &WebhookSender, idle_minutes: u64) -> Result<()> {
+fn send_active_notification(notifier: &BlockingNotifier, idle_minutes: u64) -> Result<()> {
     let additional_fields = vec![
         ("Was Idle For".to_string(), format!("{} minutes", idle_minutes)),
     ];
     
-    webhook.send(
-        EventCategory::Idle,
+    // Someone is at a machine they have not unlocked
+    let severity = if session::is_locked() { Severity::Warning } else { Severity::Info };
+    
+    notifier.notify(&Event::new(
+        EventCategory::IDLE,
+        "active",
         "System Active",
         "System has returned from idle state",
-        additional_fields
-    )
+    ).with_fields(additional_fields).with_severity(severity))
 }
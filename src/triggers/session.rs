@@ -0,0 +1,66 @@
+#[cfg(not(target_os = "windows"))]
+use std::process::Command;
+
+/// Whether the user session is locked; `false` when it cannot be told.
+///
+/// Runs external tools on Linux and macOS, so call it off the runtime
+/// worker threads.
+#[cfg(target_os = "linux")]
+pub fn is_locked() -> bool {
+    // logind tracks the lock state of graphical sessions
+    let Some(sessions) = output("loginctl", &["list-sessions", "--no-legend"]) else {
+        return false;
+    };
+    
+    let mut graphical = 0;
+    let mut locked = 0;
+    for id in sessions.lines().filter_map(|line| line.split_whitespace().next()) {
+        let Some(properties) = output("loginctl", &["show-session", id, "-p", "Type", "-p", "LockedHint"]) else {
+            continue;
+        };
+        
+        if properties.lines().any(|line| line == "Type=x11" || line == "Type=wayland") {
+            graphical += 1;
+            if properties.lines().any(|line| line == "LockedHint=yes") {
+                locked += 1;
+            }
+        }
+    }
+    
+    graphical > 0 && locked == graphical
+}
+
+#[cfg(target_os = "macos")]
+pub fn is_locked() -> bool {
+    // The window server publishes the lock state in the I/O Registry root
+    output("ioreg", &["-n", "Root", "-d1"])
+        .is_some_and(|registry| registry.contains("\"CGSSessionScreenIsLocked\"=Yes"))
+}
+
+#[cfg(target_os = "windows")]
+pub fn is_locked() -> bool {
+    use sysinfo::{System, SystemExt};
+    
+    // The lock screen is shown by LogonUI
+    let mut system = System::new();
+    system.refresh_processes();
+    
+    let locked = system.processes_by_exact_name("LogonUI.exe").next().is_some();
+    locked
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+pub fn is_locked() -> bool {
+    false
+}
+
+/// Standard output of a command that succeeded
+#[cfg(not(target_os = "windows"))]
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
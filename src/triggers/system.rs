@@ -1,8 +1,20 @@
 use anyhow::Result;
 use sysinfo::{System, SystemExt};
-use crate::webhook::{EventCategory, WebhookSender};
+use crate::webhook::{Event, EventCategory, Notifier, Severity};
 
-pub fn send_boot_notification(webhook: &WebhookSender) -> Result<()> {
+pub async fn send_boot_notification(notifier: &dyn Notifier) -> Result<()> {
+    // Collecting system information blocks, so keep it off the runtime workers
+    let additional_fields = tokio::task::spawn_blocking(get_boot_info).await?;
+    
+    notifier.notify(&Event::new(
+        EventCategory::SYSTEM,
+        "boot",
+        "System Started",
+        "The system has been started or RAA has been launched.",
+    ).with_fields(additional_fields).with_severity(Severity::Info)).await
+}
+
+fn get_boot_info() -> Vec<(String, String)> {
     // Get system information
     let mut system = System::new_all();
     system.refresh_all();
@@ -17,19 +29,12 @@ pub fn send_boot_notification(webhook: &WebhookSender) -> Result<()> {
     let hostname = system.host_name().unwrap_or_else(|| "Unknown hostname".to_string());
     let uptime = system.uptime();
     
-    let additional_fields = vec![
+    vec![
         ("OS".to_string(), os_version),
         ("Kernel".to_string(), kernel_version),
         ("Host".to_string(), hostname),
         ("Uptime".to_string(), format!("{} seconds", uptime)),
-    ];
-    
-    webhook.send(
-        EventCategory::System,
-        "System Started",
-        "The system has been started or RAA has been launched.",
-        additional_fields
-    )
+    ]
 }
 
 pub fn get_system_info() -> Vec<(String, String)> {
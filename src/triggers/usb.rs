@@ -1,9 +1,10 @@
 use anyhow::Result;
-use notify::{Watcher, RecursiveMode, EventKind, event::EventKind::*};
+use notify::{Watcher, RecommendedWatcher, RecursiveMode, event::EventKind::*};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::channel;
-use std::time::Duration;
-use crate::webhook::{EventCategory, WebhookSender};
+use crate::webhook::{BlockingNotifier, Event, EventCategory, Notifier, Severity};
+use super::session;
 
 #[cfg(target_os = "macos")]
 const USB_PATH: &str = "/Volumes";
@@ -15,12 +16,16 @@ const USB_PATH: &str = ""; // Will use drive letters monitoring instead
 const USB_PATH: &str = "/media";
 
 pub struct UsbMonitor {
-    webhook: WebhookSender,
+    notifier: Arc<dyn Notifier>,
+    watcher: Mutex<Option<RecommendedWatcher>>,
 }
 
 impl UsbMonitor {
-    pub fn new(webhook: WebhookSender) -> Self {
-        Self { webhook }
+    pub fn new(notifier: Arc<dyn Notifier>) -> Self {
+        Self {
+            notifier,
+            watcher: Mutex::new(None),
+        }
     }
     
     pub fn start_monitoring(&self) -> Result<()> {
@@ -56,19 +61,24 @@ impl UsbMonitor {
             }
         }
         
-        // Handle events
+        // Keep the watcher alive for as long as the monitor is running
+        *self.watcher.lock().unwrap() = Some(watcher);
+        
+        // Handle events; the loop ends once the watcher is dropped by `stop`
+        let notifier = BlockingNotifier::new(Arc::clone(&self.notifier))?;
         std::thread::spawn(move || {
             for event in rx {
-                match event.kind {
-                    Create(_) => {
-                        log::info!("USB device connected: {:?}", event.paths);
-                        // Handle device connected event
-                    },
-                    Remove(_) => {
-                        log::info!("USB device disconnected: {:?}", event.paths);
-                        // Handle device disconnected event
-                    },
-                    _ => {}
+                let action = match event.kind {
+                    Create(_) => "Connected",
+                    Remove(_) => "Disconnected",
+                    _ => continue,
+                };
+                
+                for path in &event.paths {
+                    log::info!("USB device {}: {:?}", action.to_lowercase(), path);
+                    
+                    notifier.notify(&usb_event(action, &path.display().to_string(), session::is_locked()))
+                        .unwrap_or_else(|e| log::error!("Failed to send USB notification: {}", e));
                 }
             }
         });
@@ -76,19 +86,36 @@ impl UsbMonitor {
         Ok(())
     }
     
-    pub fn send_usb_notification(&self, action: &str, device: &str) -> Result<()> {
-        let additional_fields = vec![
-            ("Action".to_string(), action.to_string()),
-            ("Device".to_string(), device.to_string()),
-        ];
-        
-        self.webhook.send(
-            EventCategory::Usb,
-            &format!("USB Device {}", action),
-            &format!("USB device has been {}", action.to_lowercase()),
-            additional_fields
-        )
+    pub fn stop(&self) {
+        self.watcher.lock().unwrap().take();
     }
+    
+    pub async fn send_usb_notification(&self, action: &str, device: &str) -> Result<()> {
+        let locked = tokio::task::spawn_blocking(session::is_locked).await?;
+        self.notifier.notify(&usb_event(action, device, locked)).await
+    }
+}
+
+fn usb_event(action: &str, device: &str, locked: bool) -> Event {
+    // New devices may be someone copying data off the machine, and nobody
+    // should be plugging anything into a locked one
+    let severity = match (action, locked) {
+        ("Connected", true) => Severity::Critical,
+        ("Connected", false) | (_, true) => Severity::Warning,
+        _ => Severity::Info,
+    };
+    
+    let additional_fields = vec![
+        ("Action".to_string(), action.to_string()),
+        ("Device".to_string(), device.to_string()),
+    ];
+    
+    Event::new(
+        EventCategory::USB,
+        &format!("usb_{}", action.to_lowercase()),
+        &format!("USB Device {}", action),
+        &format!("USB device has been {}", action.to_lowercase()),
+    ).with_fields(additional_fields).with_severity(severity)
 }
 
 #[cfg(target_os = "windows")]
@@ -96,3 +123,18 @@ fn get_windows_drive_letters() -> Vec<char> {
     // This is a simplified version, actual implementation would use Windows API
     ('A'..='Z').collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn severity_depends_on_lock_state() {
+        let severity = |action, locked| usb_event(action, "/media/stick", locked).severity;
+        
+        assert_eq!(severity("Connected", false), Severity::Warning);
+        assert_eq!(severity("Connected", true), Severity::Critical);
+        assert_eq!(severity("Disconnected", false), Severity::Info);
+        assert_eq!(severity("Disconnected", true), Severity::Warning);
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Wait until the process is asked to shut down (Ctrl+C, or SIGTERM on Unix)
+pub async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut terminate = signal(SignalKind::terminate())?;
+        
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+    }
+    
+    Ok(())
+}
+
+/// Replace the file at `path` with `contents` without leaving a partially
+/// written file behind if the process dies midway.
+///
+/// On Unix the file is only readable by its owner: everything written this
+/// way (config, backups, queued deliveries) may contain webhook secrets.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path.file_name()
+        .with_context(|| format!("Invalid file path {:?}", path))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    
+    // A leftover from a crash would keep its permissions
+    let _ = fs::remove_file(&tmp_path);
+    
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    
+    let mut file = options.open(&tmp_path)
+        .with_context(|| format!("Failed to create {:?}", tmp_path))?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move {:?} into place", tmp_path))?;
+    
+    // Make the rename itself survive a crash
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Failed to sync {:?}", dir))?;
+    }
+    
+    Ok(())
+}
+
+/// Create `path` and any missing parents, accessible only by their owner
+/// on Unix
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    
+    builder.create(path)
+        .with_context(|| format!("Failed to create directory {:?}", path))
+}
+
+/// Largest index of `s` at or below `index` that starts a character, so
+/// slicing there never splits one; `str::floor_char_boundary` needs Rust
+/// 1.91, newer than our `rust-version`
+pub fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    
+    s.char_indices()
+        .map(|(start, _)| start)
+        .take_while(|start| *start <= index)
+        .last()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn floor_char_boundary_never_splits_characters() {
+        let s = "aé€😀";
+        
+        let boundaries: Vec<_> = (0..=s.len() + 1).map(|index| floor_char_boundary(s, index)).collect();
+        assert_eq!(boundaries, [0, 1, 1, 3, 3, 3, 6, 6, 6, 6, 10, 10]);
+        assert_eq!(floor_char_boundary("", 3), 0);
+    }
+    
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+    
+    #[test]
+    fn write_atomic_replaces_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["config.json"]);
+    }
+    
+    #[test]
+    fn write_atomic_leaves_the_file_alone_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        write_atomic(&path, b"original").unwrap();
+        
+        // The temporary file cannot be created in a missing directory
+        assert!(write_atomic(&dir.path().join("missing").join("config.json"), b"new").is_err());
+        // Nor moved over a directory
+        let target = dir.path().join("target");
+        fs::create_dir(&target).unwrap();
+        assert!(write_atomic(&target, b"new").is_err());
+        
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_keeps_files_private() {
+        use std::os::unix::fs::PermissionsExt;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        
+        // Replacing a readable file, with a readable leftover from a crash
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let tmp_path = dir.path().join(".config.json.tmp");
+        fs::write(&tmp_path, "partial").unwrap();
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o644)).unwrap();
+        
+        write_atomic(&path, b"secret").unwrap();
+        
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        assert_eq!(mode(&path), 0o600);
+        assert!(!tmp_path.exists());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn create_private_dir_restricts_new_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raa").join("outbox");
+        
+        create_private_dir(&path).unwrap();
+        assert_eq!(mode(&path), 0o700);
+        assert_eq!(mode(&dir.path().join("raa")), 0o700);
+        
+        // Existing directories are fine
+        create_private_dir(&path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use reqwest::{Certificate, Identity, Proxy};
+use std::fs;
+use std::time::Duration;
+use crate::config::{ClientCertificate, HttpConfig};
+
+/// How long idle connections are kept around for reuse
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Interval of TCP keepalive probes on pooled connections
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Build the HTTP client used for webhook delivery.
+///
+/// `reqwest::Client` is reference counted, so every clone shares the same
+/// connection pool, TLS sessions and configuration.
+pub fn build_client(http: &HttpConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!("RAA/", env!("CARGO_PKG_VERSION")))
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .connect_timeout(Duration::from_secs(http.connect_timeout))
+        .timeout(Duration::from_secs(http.request_timeout));
+    
+    if let Some(proxy) = &http.proxy {
+        let proxy = Proxy::all(proxy.expose())
+            .with_context(|| format!("Invalid proxy URL {:?}", proxy))?;
+        builder = builder.proxy(proxy);
+    }
+    
+    for path in &http.ca_certificates {
+        let pem = fs::read(path)
+            .with_context(|| format!("Failed to read CA certificate {:?}", path))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA certificate {:?}", path))?;
+        
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    
+    if let Some(client_certificate) = &http.client_certificate {
+        builder = builder.identity(load_identity(client_certificate)?);
+    }
+    
+    builder.build().context("Failed to build HTTP client")
+}
+
+fn load_identity(client_certificate: &ClientCertificate) -> Result<Identity> {
+    let path = &client_certificate.certificate;
+    let certificate = fs::read(path)
+        .with_context(|| format!("Failed to read client certificate {:?}", path))?;
+    
+    let identity = match &client_certificate.key {
+        Some(key_path) => {
+            let key = fs::read(key_path)
+                .with_context(|| format!("Failed to read client key {:?}", key_path))?;
+            Identity::from_pkcs8_pem(&certificate, &key)
+        }
+        None => {
+            let password = client_certificate.password.as_deref().unwrap_or("");
+            Identity::from_pkcs12_der(&certificate, password)
+        }
+    };
+    
+    identity.with_context(|| format!("Invalid client certificate {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::secret::Secret;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    
+    #[tokio::test]
+    async fn gives_up_after_the_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        
+        // Accepts the connection but never answers
+        let _server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(stream);
+        });
+        
+        let client = build_client(&HttpConfig { request_timeout: 1, ..HttpConfig::default() }).unwrap();
+        let started = std::time::Instant::now();
+        
+        let error = client.post(&url).send().await.unwrap_err();
+        assert!(error.is_timeout(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+    
+    #[tokio::test]
+    async fn sends_requests_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        
+        let client = build_client(&HttpConfig { proxy: Some(Secret::new(&proxy)), ..HttpConfig::default() }).unwrap();
+        let response = client.get("http://webhook.invalid/hook").send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 204);
+        
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET http://webhook.invalid/hook HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains(concat!("RAA/", env!("CARGO_PKG_VERSION"))), "{}", request);
+    }
+    
+    #[test]
+    fn rejects_invalid_settings() {
+        let invalid_proxy = HttpConfig { proxy: Some(Secret::new("not a url")), ..HttpConfig::default() };
+        let error = build_client(&invalid_proxy).unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Invalid proxy URL"), "{:#}", error);
+        
+        let missing_ca = HttpConfig { ca_certificates: vec!["/nonexistent/ca.pem".into()], ..HttpConfig::default() };
+        let error = build_client(&missing_ca).unwrap_err();
+        assert!(error.to_string().contains("Failed to read CA certificate"), "{:#}", error);
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use serde::Serialize;
+use super::{Event, Payload};
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    username: String,
+    content: String,
+    #[serde(rename = "avatar_url")]
+    avatar_url: Option<String>,
+    embeds: Vec<WebhookEmbed>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookEmbed {
+    title: String,
+    description: Option<String>,
+    color: u32,
+    timestamp: String,
+    fields: Vec<WebhookField>,
+    footer: WebhookFooter,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookFooter {
+    text: String,
+}
+
+/// Render an event as a Discord webhook message with a single embed
+pub fn render(event: &Event, device_name: &str) -> Result<Payload> {
+    // Create fields for the embed
+    let mut fields = Vec::new();
+    
+    // Add message as a field
+    fields.push(WebhookField {
+        name: "Message".to_string(),
+        value: event.message.clone(),
+        inline: false,
+    });
+    
+    // Add any additional fields
+    for (name, value) in &event.fields {
+        fields.push(WebhookField {
+            name: name.clone(),
+            value: value.clone(),
+            inline: true,
+        });
+    }
+    
+    // Create the webhook payload
+    let payload = WebhookPayload {
+        username: format!("RAA - {}", device_name),
+        content: "".to_string(),
+        avatar_url: Some("https://i.imgur.com/example.png".to_string()),
+        embeds: vec![WebhookEmbed {
+            title: event.title.clone(),
+            description: None,
+            color: event.color(),
+            timestamp: event.timestamp.to_rfc3339(),
+            fields,
+            footer: WebhookFooter {
+                text: format!("RAA v{} | Device: {}", env!("CARGO_PKG_VERSION"), device_name),
+            },
+        }],
+    };
+    
+    Payload::json(&payload)
+}
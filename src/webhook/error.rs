@@ -0,0 +1,163 @@
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Why a webhook request was not accepted
+#[derive(Debug, Error)]
+pub enum DeliveryError {
+    /// The request never got a response (DNS, connect, TLS, timeout)
+    #[error(transparent)]
+    Transport(reqwest::Error),
+    
+    /// The endpoint asked us to slow down
+    #[error("rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    
+    /// The endpoint failed to handle the request; worth retrying later
+    #[error("server error {status}: {body}")]
+    Server { status: StatusCode, body: String },
+    
+    /// The endpoint refused the request; retrying will not help until the
+    /// configuration is fixed (revoked webhook, wrong URL, bad payload)
+    #[error("rejected with {status}, check the webhook configuration: {body}")]
+    Rejected { status: StatusCode, body: String },
+    
+    /// A queued delivery whose destination was removed from the config
+    #[error("destination '{0}' is no longer configured")]
+    UnknownDestination(String),
+}
+
+/// Drops the URL, which may hold a token, so the error can be logged and
+/// kept in the outbox
+impl From<reqwest::Error> for DeliveryError {
+    fn from(error: reqwest::Error) -> Self {
+        DeliveryError::Transport(error.without_url())
+    }
+}
+
+impl DeliveryError {
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, DeliveryError::Rejected { .. } | DeliveryError::UnknownDestination(_))
+    }
+    
+    /// Delay requested by the endpoint before the next attempt
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DeliveryError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an error returned by a delivery is permanent, see
+/// `DeliveryError::is_permanent`
+pub fn is_permanent(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<DeliveryError>().is_some_and(DeliveryError::is_permanent)
+}
+
+/// Delay before the next request is allowed, from `Retry-After` or the
+/// Discord-style `X-RateLimit-*` headers
+pub fn rate_limit_delay(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(value) = header_str(headers, "retry-after") {
+        if let Some(delay) = parse_seconds(value) {
+            return Some(delay);
+        }
+        
+        // Retry-After may also be an HTTP date
+        if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+            let delay = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+            return Some(delay.to_std().unwrap_or_default());
+        }
+    }
+    
+    if let Some(delay) = header_str(headers, "x-ratelimit-reset-after").and_then(parse_seconds) {
+        return Some(delay);
+    }
+    
+    header_str(headers, "x-ratelimit-reset")
+        .and_then(|value| value.parse::<f64>().ok())
+        .map(|reset| {
+            let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+            Duration::from_secs_f64((reset - now).max(0.0))
+        })
+}
+
+/// Whether the rate-limit headers say the current bucket is used up
+pub fn rate_limit_exhausted(headers: &HeaderMap) -> bool {
+    header_str(headers, "x-ratelimit-remaining")
+        .and_then(|value| value.parse::<u64>().ok())
+        == Some(0)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim)
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value.parse::<f64>().ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs.iter()
+            .map(|(name, value)| (reqwest::header::HeaderName::from_static(name), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+    
+    #[test]
+    fn reads_retry_after_seconds() {
+        assert_eq!(rate_limit_delay(&headers(&[("retry-after", "120")])), Some(Duration::from_secs(120)));
+        assert_eq!(rate_limit_delay(&headers(&[("retry-after", " 1.5 ")])), Some(Duration::from_millis(1500)));
+        assert_eq!(rate_limit_delay(&headers(&[("retry-after", "-1")])), None);
+        assert_eq!(rate_limit_delay(&headers(&[])), None);
+    }
+    
+    #[test]
+    fn reads_retry_after_dates() {
+        let in_a_minute = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = rate_limit_delay(&headers(&[("retry-after", &in_a_minute)])).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+        
+        let past = "Mon, 01 Jan 2024 00:00:00 GMT";
+        assert_eq!(rate_limit_delay(&headers(&[("retry-after", past)])), Some(Duration::ZERO));
+    }
+    
+    #[test]
+    fn falls_back_to_rate_limit_headers() {
+        let reset_after = headers(&[("retry-after", "soon"), ("x-ratelimit-reset-after", "2.5")]);
+        assert_eq!(rate_limit_delay(&reset_after), Some(Duration::from_millis(2500)));
+        
+        let reset = (chrono::Utc::now().timestamp() + 30).to_string();
+        let delay = rate_limit_delay(&headers(&[("x-ratelimit-reset", &reset)])).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30), "{:?}", delay);
+    }
+    
+    #[test]
+    fn detects_exhausted_rate_limits() {
+        assert!(rate_limit_exhausted(&headers(&[("x-ratelimit-remaining", "0")])));
+        assert!(!rate_limit_exhausted(&headers(&[("x-ratelimit-remaining", "3")])));
+        assert!(!rate_limit_exhausted(&headers(&[])));
+    }
+    
+    #[test]
+    fn classifies_permanent_errors() {
+        let rejected = DeliveryError::Rejected { status: StatusCode::NOT_FOUND, body: String::new() };
+        let server = DeliveryError::Server { status: StatusCode::BAD_GATEWAY, body: String::new() };
+        let limited = DeliveryError::RateLimited { retry_after: Duration::from_secs(5) };
+        
+        assert!(is_permanent(&rejected.into()));
+        assert!(is_permanent(&DeliveryError::UnknownDestination("a".to_string()).into()));
+        assert!(!is_permanent(&server.into()));
+        assert!(!is_permanent(&anyhow::anyhow!("connection refused")));
+        
+        assert_eq!(limited.retry_after(), Some(Duration::from_secs(5)));
+        assert!(!limited.is_permanent());
+    }
+}
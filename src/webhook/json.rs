@@ -0,0 +1,117 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use super::{Event, Payload, Severity};
+
+/// Version of the `JsonEvent` schema.
+///
+/// Bumped whenever a field is removed or changes meaning; adding optional
+/// fields does not change the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable event document posted to custom collectors.
+///
+/// This type is the published schema, so collectors written in Rust can
+/// deserialize requests with it directly.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JsonEvent {
+    pub schema_version: u32,
+    pub event_id: String,
+    pub category: String,
+    pub kind: String,
+    /// Added without a version bump; absent in documents from older agents
+    #[serde(default)]
+    pub severity: Severity,
+    pub device_name: String,
+    /// RFC 3339 timestamp in UTC
+    pub timestamp: String,
+    pub title: String,
+    pub message: String,
+    pub fields: BTreeMap<String, String>,
+    pub agent_version: String,
+}
+
+impl JsonEvent {
+    pub fn from_event(event: &Event, device_name: &str) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            event_id: event.id.clone(),
+            category: event.category.to_string(),
+            kind: event.kind.clone(),
+            severity: event.severity,
+            device_name: device_name.to_string(),
+            timestamp: event.timestamp.to_rfc3339(),
+            title: event.title.clone(),
+            message: event.message.clone(),
+            fields: event.fields.iter().cloned().collect(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Render an event in the versioned RAA JSON schema
+pub fn render(event: &Event, device_name: &str) -> Result<Payload> {
+    Payload::json(&JsonEvent::from_event(event, device_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::EventCategory;
+    use serde_json::{json, Value};
+    
+    fn event() -> Event {
+        Event::new(EventCategory::USB, "usb_connected", "USB connected", "A drive was connected")
+            .with_fields(vec![("Device".to_string(), "SanDisk".to_string())])
+            .with_severity(Severity::Warning)
+    }
+    
+    #[test]
+    fn renders_the_published_schema() {
+        let event = event();
+        let payload = render(&event, "office-pc").unwrap();
+        assert_eq!(payload.content_type, "application/json");
+        
+        let value: Value = serde_json::from_str(&payload.body).unwrap();
+        assert_eq!(value, json!({
+            "schema_version": 1,
+            "event_id": event.id,
+            "category": "usb",
+            "kind": "usb_connected",
+            "severity": "warning",
+            "device_name": "office-pc",
+            "timestamp": event.timestamp.to_rfc3339(),
+            "title": "USB connected",
+            "message": "A drive was connected",
+            "fields": { "Device": "SanDisk" },
+            "agent_version": env!("CARGO_PKG_VERSION"),
+        }));
+    }
+    
+    #[test]
+    fn round_trips_through_the_schema_type() {
+        let event = event();
+        let payload = render(&event, "office-pc").unwrap();
+        
+        let document: JsonEvent = serde_json::from_str(&payload.body).unwrap();
+        assert_eq!(document, JsonEvent::from_event(&event, "office-pc"));
+    }
+    
+    #[test]
+    fn reads_documents_without_severity() {
+        let document: JsonEvent = serde_json::from_value(json!({
+            "schema_version": 1,
+            "event_id": "1",
+            "category": "system",
+            "kind": "startup",
+            "device_name": "office-pc",
+            "timestamp": "2024-01-01T00:00:00+00:00",
+            "title": "Started",
+            "message": "The agent started",
+            "fields": {},
+            "agent_version": "0.1.0",
+        })).unwrap();
+        
+        assert_eq!(document.severity, Severity::Info);
+    }
+}
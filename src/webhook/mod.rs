@@ -1,215 +1,756 @@
-use anyhow::Result;
-use serde::Serialize;
-use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use crate::config::HttpConfig;
+use crate::utils;
+use error::DeliveryError;
+use outbox::{FlushStats, Outbox, OutboxEntry};
+use routing::{Destination, Router};
 
-#[derive(Debug, Serialize)]
-struct WebhookPayload {
-    username: String,
-    content: String,
-    #[serde(rename = "avatar_url")]
-    avatar_url: Option<String>,
-    embeds: Vec<WebhookEmbed>,
-}
+/// Longest rate-limit delay that is waited out in place; longer delays are
+/// left to the outbox
+const MAX_INLINE_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+
+/// How often a rate-limited request is retried in place
+const MAX_RATE_LIMIT_RETRIES: u32 = 2;
+
+/// Delay assumed when a 429 response carries no rate-limit headers
+const DEFAULT_RATE_LIMIT_DELAY: Duration = Duration::from_secs(5);
+
+pub mod client;
+pub mod discord;
+pub mod error;
+pub mod json;
+pub mod ntfy;
+pub mod outbox;
+pub mod routing;
+pub mod signature;
+pub mod slack;
+pub mod teams;
+#[cfg(test)]
+pub(crate) mod test_server;
 
-#[derive(Debug, Serialize)]
-struct WebhookEmbed {
-    title: String,
-    description: Option<String>,
-    color: u32,
-    timestamp: String,
-    fields: Vec<WebhookField>,
-    footer: WebhookFooter,
+/// The kind of trigger an event comes from, e.g. `usb`.
+///
+/// Categories are open: a trigger names its own and routes match on the
+/// name, so adding one needs no changes here.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EventCategory(Cow<'static, str>);
+
+impl EventCategory {
+    pub const SYSTEM: Self = Self(Cow::Borrowed("system"));
+    pub const USB: Self = Self(Cow::Borrowed("usb"));
+    pub const IDLE: Self = Self(Cow::Borrowed("idle"));
+    
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    
+    /// Accent colour used by chat formats that support one
+    pub fn color(&self) -> u32 {
+        match self.as_str() {
+            "system" => 0x3498db, // Blue
+            "usb" => 0xe74c3c,    // Red
+            "idle" => 0xf1c40f,   // Yellow
+            _ => 0x95a5a6,        // Grey
+        }
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct WebhookField {
-    name: String,
-    value: String,
-    inline: bool,
+impl std::fmt::Display for EventCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct WebhookFooter {
-    text: String,
+impl std::str::FromStr for EventCategory {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.to_lowercase();
+        
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            anyhow::bail!("Invalid event category '{}' (use letters, digits, '_' and '-')", s);
+        }
+        Ok(Self(Cow::Owned(name)))
+    }
 }
 
-pub enum EventCategory {
-    System,
-    Usb,
-    Idle,
+/// How urgent an event is, from least to most
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Routine activity such as boots and heartbeats
+    #[default]
+    Info,
+    /// Something worth a look, e.g. a USB device being connected
+    Warning,
+    /// Something that needs attention right away, e.g. a USB device being
+    /// connected to a locked machine
+    Critical,
 }
 
-impl std::fmt::Display for EventCategory {
+impl std::fmt::Display for Severity {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            EventCategory::System => write!(f, "system"),
-            EventCategory::Usb => write!(f, "usb"),
-            EventCategory::Idle => write!(f, "idle"),
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
         }
     }
 }
 
-pub struct WebhookSender {
-    client: reqwest::blocking::Client,
-    device_name: String,
-    webhooks: std::collections::HashMap<String, String>,
+impl std::str::FromStr for Severity {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(anyhow::anyhow!("Unknown severity '{}' (expected info, warning or critical)", s)),
+        }
+    }
+}
+
+/// A notification produced by one of the triggers
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Unique identifier, stable across redeliveries of the same event
+    pub id: String,
+    pub category: EventCategory,
+    /// Machine-readable event type within the category, e.g. `usb_connected`
+    pub kind: String,
+    pub severity: Severity,
+    pub title: String,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
-impl Clone for WebhookSender {
-    fn clone(&self) -> Self {
+impl Event {
+    pub fn new(category: EventCategory, kind: &str, title: &str, message: &str) -> Self {
         Self {
-            client: reqwest::blocking::Client::new(),
-            device_name: self.device_name.clone(),
-            webhooks: self.webhooks.clone(),
+            id: uuid::Uuid::new_v4().to_string(),
+            category,
+            kind: kind.to_string(),
+            severity: Severity::Info,
+            title: title.to_string(),
+            message: message.to_string(),
+            fields: Vec::new(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+    
+    pub fn with_fields(mut self, fields: Vec<(String, String)>) -> Self {
+        self.fields = fields;
+        self
+    }
+    
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+    
+    /// Accent colour: the category colour for informational events, orange
+    /// for warnings and dark red for critical events
+    pub fn color(&self) -> u32 {
+        match self.severity {
+            Severity::Info => self.category.color(),
+            Severity::Warning => 0xe67e22,
+            Severity::Critical => 0x992d22,
         }
     }
 }
 
-impl WebhookSender {
-    pub fn new(device_name: String, system: String, usb: String, idle: String) -> Self {
-        let client = reqwest::blocking::Client::new();
-        let mut webhooks = std::collections::HashMap::new();
+/// Future returned by `Notifier::notify`
+pub type NotifyFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Something that delivers trigger events to the outside world
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(&'a self, event: &'a Event) -> NotifyFuture<'a>;
+}
+
+/// Blocking wrapper around a `Notifier` for monitors that run on their own
+/// threads instead of the tokio runtime
+#[derive(Clone)]
+pub struct BlockingNotifier {
+    notifier: Arc<dyn Notifier>,
+    runtime: Handle,
+}
+
+impl BlockingNotifier {
+    /// Wrap `notifier`, delivering on the runtime of the calling context
+    pub fn new(notifier: Arc<dyn Notifier>) -> Result<Self> {
+        let runtime = Handle::try_current()
+            .context("Blocking notifiers must be created from within the tokio runtime")?;
         
-        webhooks.insert("system".to_string(), system);
-        webhooks.insert("usb".to_string(), usb);
-        webhooks.insert("idle".to_string(), idle);
+        Ok(Self { notifier, runtime })
+    }
+    
+    /// Deliver an event, blocking the current thread until it is done.
+    ///
+    /// Must not be called from a runtime worker thread.
+    pub fn notify(&self, event: &Event) -> Result<()> {
+        self.runtime.block_on(self.notifier.notify(event))
+    }
+}
+
+/// Wire format of the requests sent to a webhook
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// Discord webhook message with an embed
+    #[default]
+    Discord,
+    /// Plain text message published to an ntfy topic
+    Ntfy,
+    /// Slack incoming-webhook message with Block Kit blocks
+    Slack,
+    /// Microsoft Teams connector MessageCard
+    Teams,
+    /// Adaptive Card for Teams workflow (Power Automate) webhooks
+    #[serde(rename = "teams-adaptive")]
+    TeamsAdaptive,
+    /// Versioned RAA event schema for custom collectors
+    Json,
+}
+
+impl PayloadFormat {
+    pub fn render(&self, event: &Event, device_name: &str) -> Result<Payload> {
+        match self {
+            PayloadFormat::Discord => discord::render(event, device_name),
+            PayloadFormat::Ntfy => ntfy::render(event, device_name),
+            PayloadFormat::Slack => slack::render(event, device_name),
+            PayloadFormat::Teams => teams::render_message_card(event, device_name),
+            PayloadFormat::TeamsAdaptive => teams::render_adaptive_card(event, device_name),
+            PayloadFormat::Json => json::render(event, device_name),
+        }
+    }
+}
+
+/// A rendered request body together with the headers it needs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payload {
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Payload {
+    pub fn json<T: Serialize>(value: &T) -> Result<Self> {
+        Ok(Self {
+            content_type: "application/json".to_string(),
+            headers: Vec::new(),
+            body: serde_json::to_string(value).context("Failed to serialize webhook payload")?,
+        })
+    }
+}
+
+/// What happened to an event at one destination
+#[derive(Debug)]
+pub enum Outcome {
+    Sent,
+    /// Not delivered yet; the outbox retries it
+    Queued,
+    /// The destination's filters exclude the event
+    Skipped,
+    Failed(anyhow::Error),
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Sent => write!(f, "sent"),
+            Outcome::Queued => write!(f, "queued for retry"),
+            Outcome::Skipped => write!(f, "skipped by filters"),
+            Outcome::Failed(e) => write!(f, "failed: {:#}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DestinationOutcome {
+    /// Label of the destination, see `Destination::label`
+    pub destination: String,
+    pub outcome: Outcome,
+}
+
+/// Per-destination outcomes of delivering one event
+#[derive(Debug, Default)]
+pub struct DeliveryReport {
+    pub outcomes: Vec<DestinationOutcome>,
+}
+
+impl DeliveryReport {
+    pub fn is_success(&self) -> bool {
+        !self.outcomes.iter().any(|o| matches!(o.outcome, Outcome::Failed(_)))
+    }
+    
+    /// Fail if any destination failed, naming all of them
+    pub fn into_result(self) -> Result<()> {
+        let failures: Vec<_> = self.outcomes.into_iter()
+            .filter_map(|o| match o.outcome {
+                Outcome::Failed(e) => Some(format!("{}: {:#}", o.destination, e)),
+                _ => None,
+            })
+            .collect();
         
+        match failures.as_slice() {
+            [] => Ok(()),
+            [failure] => Err(anyhow::anyhow!("{}", failure)),
+            failures => Err(anyhow::anyhow!("{} destinations failed: {}", failures.len(), failures.join("; "))),
+        }
+    }
+}
+
+/// Configuration of a sender that can be replaced while it is in use
+struct Settings {
+    client: reqwest::Client,
+    device_name: String,
+    router: Router,
+}
+
+/// Settings checked by `WebhookSender::prepare_reload`
+pub struct PreparedSettings(Settings);
+
+/// Delivers events to the configured webhooks.
+///
+/// Clones are cheap and share the HTTP client, destinations, outbox and
+/// rate-limit state, so a `reload` applies to all of them.
+#[derive(Clone)]
+pub struct WebhookSender {
+    /// Every delivery works on one snapshot, so a reload never mixes the old
+    /// and new destinations of an event
+    settings: Arc<RwLock<Arc<Settings>>>,
+    outbox: Option<Arc<Outbox>>,
+    /// Per-URL time before which no request may be sent
+    rate_limits: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl WebhookSender {
+    /// Sender with the default HTTP settings
+    pub fn new(device_name: String, router: Router) -> Result<Self> {
+        let client = client::build_client(&HttpConfig::default())?;
+        Ok(Self::with_settings(Settings { client, device_name, router }))
+    }
+    
+    pub fn from_config(config: &crate::Config) -> Result<Self> {
+        Ok(Self::with_settings(Settings::from_config(config)?))
+    }
+    
+    fn with_settings(settings: Settings) -> Self {
         Self {
-            client,
-            device_name,
-            webhooks,
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            outbox: None,
+            rate_limits: Arc::new(Mutex::new(HashMap::new())),
         }
     }
     
-    pub fn from_config(config: &crate::Config) -> Self {
-        Self::new(
-            config.device_name.clone(),
-            config.webhooks.system.clone(),
-            config.webhooks.usb.clone(),
-            config.webhooks.idle.clone(),
-        )
+    /// Build the destinations and HTTP settings of `config` without using
+    /// them yet, so a reload can fail before anything has changed
+    pub fn prepare_reload(config: &crate::Config) -> Result<PreparedSettings> {
+        Ok(PreparedSettings(Settings::from_config(config)?))
+    }
+    
+    /// Switch to prepared settings. Deliveries already in progress finish
+    /// with the previous settings.
+    pub fn apply_reload(&self, settings: PreparedSettings) {
+        *self.settings.write().unwrap() = Arc::new(settings.0);
+    }
+    
+    fn settings(&self) -> Arc<Settings> {
+        Arc::clone(&self.settings.read().unwrap())
+    }
+    
+    /// Queue failed deliveries in `outbox` instead of returning the error
+    pub fn with_outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(Arc::new(outbox));
+        self
+    }
+    
+    pub async fn send(&self, category: EventCategory, kind: &str, title: &str, message: &str, additional_fields: Vec<(String, String)>) -> DeliveryReport {
+        self.deliver(&Event::new(category, kind, title, message).with_fields(additional_fields)).await
+    }
+    
+    pub async fn send_event(&self, event: &Event) -> DeliveryReport {
+        self.deliver(event).await
+    }
+    
+    /// Retry queued deliveries that are due
+    pub async fn flush_outbox(&self) -> Result<FlushStats> {
+        match &self.outbox {
+            Some(outbox) => outbox.flush(|entry| async move {
+                let settings = self.settings();
+                
+                // URLs and secrets stay in the config rather than the queue files
+                let target = &settings.router.destination(&entry.destination)
+                    .ok_or_else(|| DeliveryError::UnknownDestination(entry.destination.clone()))?
+                    .target;
+                Ok(self.post(&settings.client, &target.url, &entry.payload, target.secret.as_deref()).await?)
+            }).await,
+            None => Ok(FlushStats::default()),
+        }
     }
     
-    pub fn send(&self, category: EventCategory, title: &str, message: &str, additional_fields: Vec<(String, String)>) -> Result<()> {
-        let category_str = category.to_string();
+    /// POST a payload, waiting out short rate limits and classifying the response
+    async fn post(&self, client: &reqwest::Client, url: &str, payload: &Payload, secret: Option<&str>) -> std::result::Result<(), DeliveryError> {
+        let mut attempt = 0;
         
-        if let Some(webhook_url) = self.webhooks.get(&category_str) {
-            // Get current ISO timestamp
-            let now = SystemTime::now();
-            let timestamp = now.duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
+        loop {
+            self.wait_for_rate_limit(url).await;
             
-            let iso_time = chrono::DateTime::<chrono::Utc>::from_utc(
-                chrono::NaiveDateTime::from_timestamp_opt(timestamp as i64, 0).unwrap_or_default(),
-                chrono::Utc,
-            ).to_rfc3339();
+            let mut request = client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, &payload.content_type);
+            for (name, value) in &payload.headers {
+                request = request.header(name, value);
+            }
             
-            // Create fields for the embed
-            let mut fields = Vec::new();
+            // Signed per attempt so the timestamp is fresh on retries
+            if let Some(secret) = secret {
+                let timestamp = chrono::Utc::now().timestamp();
+                let header = signature::sign(secret.as_bytes(), timestamp, payload.body.as_bytes());
+                request = request.header(signature::SIGNATURE_HEADER, header);
+            }
             
-            // Add message as a field
-            fields.push(WebhookField {
-                name: "Message".to_string(),
-                value: message.to_string(),
-                inline: false,
-            });
+            let response = request.body(payload.body.clone()).send().await?;
+            let status = response.status();
+            let headers = response.headers().clone();
             
-            // Add any additional fields
-            for (name, value) in additional_fields {
-                fields.push(WebhookField {
-                    name,
-                    value,
-                    inline: true,
-                });
+            if error::rate_limit_exhausted(&headers) {
+                if let Some(delay) = error::rate_limit_delay(&headers) {
+                    self.rate_limits.lock().unwrap().insert(url.to_string(), Instant::now() + delay);
+                }
             }
             
-            // Create the webhook payload
-            let payload = WebhookPayload {
-                username: format!("RAA - {}", self.device_name),
-                content: "".to_string(),
-                avatar_url: Some("https://i.imgur.com/example.png".to_string()),
-                embeds: vec![WebhookEmbed {
-                    title: title.to_string(),
-                    description: None,
-                    color: match category {
-                        EventCategory::System => 0x3498db, // Blue
-                        EventCategory::Usb => 0xe74c3c,    // Red
-                        EventCategory::Idle => 0xf1c40f,   // Yellow
-                    },
-                    timestamp: iso_time,
-                    fields,
-                    footer: WebhookFooter {
-                        text: format!("RAA v{} | Device: {}", env!("CARGO_PKG_VERSION"), self.device_name),
-                    },
-                }],
-            };
+            if status.is_success() {
+                return Ok(());
+            }
             
-            // Send the webhook
-            self.client.post(webhook_url)
-                .json(&payload)
-                .send()?;
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = error::rate_limit_delay(&headers).unwrap_or(DEFAULT_RATE_LIMIT_DELAY);
+                self.rate_limits.lock().unwrap().insert(url.to_string(), Instant::now() + retry_after);
+                
+                if attempt < MAX_RATE_LIMIT_RETRIES && retry_after <= MAX_INLINE_RATE_LIMIT_WAIT {
+                    log::warn!("Rate limited by webhook, retrying in {:?}", retry_after);
+                    attempt += 1;
+                    continue;
+                }
                 
-            log::info!("Sent {} webhook: {}", category_str, title);
-            Ok(())
-        } else {
-            log::error!("No webhook URL configured for category: {}", category_str);
-            Err(anyhow::anyhow!("No webhook URL configured for category: {}", category_str))
+                return Err(DeliveryError::RateLimited { retry_after });
+            }
+            
+            let mut body = response.text().await.unwrap_or_default();
+            body.truncate(utils::floor_char_boundary(&body, 500));
+            
+            return Err(if status.is_server_error() || status == reqwest::StatusCode::REQUEST_TIMEOUT {
+                DeliveryError::Server { status, body }
+            } else {
+                DeliveryError::Rejected { status, body }
+            });
+        }
+    }
+    
+    /// Sleep until the rate limit recorded for `url` has passed
+    async fn wait_for_rate_limit(&self, url: &str) {
+        let until = self.rate_limits.lock().unwrap().get(url).copied();
+        
+        if let Some(until) = until {
+            let delay = until.saturating_duration_since(Instant::now());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay.min(MAX_INLINE_RATE_LIMIT_WAIT)).await;
+            }
         }
     }
 }
 
-// Add some missing dependencies to Cargo.toml
-// chrono = "0.4"
-            fields.push(WebhookField {
-                name: "Message".to_string(),
-                value: message.to_string(),
-                inline: false,
-            });
-            
-            // Add additional fields
-            for (name, value) in additional_fields {
-                fields.push(WebhookField {
-                    name,
-                    value,
-                    inline: true,
-                });
-            }
-            
-            // Create the embed
-            let embed = WebhookEmbed {
-                title: title.to_string(),
-                description: None,
-                color: match category {
-                    EventCategory::System => 0x5865F2, // Discord blurple
-                    EventCategory::Usb => 0xEB459E,    // Pink
-                    EventCategory::Idle => 0xFEE75C,   // Yellow
-                },
-                timestamp: iso_time,
-                fields,
-                footer: WebhookFooter {
-                    text: format!("RAA Agent on {}", self.device_name),
-                },
-            };
-            
-            // Create the payload
-            let payload = WebhookPayload {
-                username: format!("RAA - {}", self.device_name),
-                content: "".to_string(),
-                avatar_url: Some("https://i.imgur.com/Xvt4F5W.png".to_string()),
-                embeds: vec![embed],
-            };
-            
+impl Settings {
+    fn from_config(config: &crate::Config) -> Result<Self> {
+        Ok(Self {
+            client: client::build_client(&config.http)?,
+            device_name: config.device_name.clone(),
+            router: Router::from_config(config)?,
+        })
+    }
+}
+
+impl Notifier for WebhookSender {
+    fn notify<'a>(&'a self, event: &'a Event) -> NotifyFuture<'a> {
+        Box::pin(async move { self.deliver(event).await.into_result() })
+    }
+}
+
+impl WebhookSender {
+    /// Send `event` to every destination it is routed to concurrently
+    async fn deliver(&self, event: &Event) -> DeliveryReport {
+        let settings = self.settings();
+        let targets = settings.router.targets_for(event);
+        
+        if targets.is_empty() {
+            log::debug!("No destinations for {} event {}", event.category, event.kind);
+        }
+        
+        let tasks: Vec<_> = targets.into_iter()
+            .map(|target| {
+                let sender = self.clone();
+                let settings = Arc::clone(&settings);
+                let event = event.clone();
+                
+                tokio::spawn(async move {
+                    let outcome = sender.deliver_to(&settings, &target, &event).await;
+                    DestinationOutcome { destination: target.label().to_string(), outcome }
+                })
+            })
+            .collect();
+        
+        let mut report = DeliveryReport::default();
+        for task in tasks {
+            report.outcomes.push(task.await.unwrap_or_else(|e| DestinationOutcome {
+                destination: "unknown".to_string(),
+                outcome: Outcome::Failed(anyhow::anyhow!("Delivery task failed: {}", e)),
+            }));
+        }
+        
+        report
+    }
+    
+    async fn deliver_to(&self, settings: &Settings, destination: &Destination, event: &Event) -> Outcome {
+        if !destination.target.accepts(event) {
+            return Outcome::Skipped;
+        }
+        
+        match self.try_deliver_to(settings, destination, event).await {
+            Ok(outcome) => outcome,
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+    
+    async fn try_deliver_to(&self, settings: &Settings, destination: &Destination, event: &Event) -> Result<Outcome> {
+        let target = &destination.target;
+        let category_str = event.category.to_string();
+        let label = destination.label();
+        let payload = target.format.render(event, &settings.device_name)?;
+        
+        let Some(outbox) = &self.outbox else {
             // Send the webhook
-            self.client.post(webhook_url)
-                .json(&payload)
-                .send()?;
+            self.post(&settings.client, &target.url, &payload, target.secret.as_deref()).await
+                .with_context(|| format!("Failed to send {} webhook", category_str))?;
             
-            log::info!("Sent webhook to {}: {}", category_str, title);
-            Ok(())
-        } else {
-            log::error!("No webhook URL configured for category: {}", category_str);
-            Err(anyhow::anyhow!("No webhook URL configured for category: {}", category_str))
+            log::info!("Sent {} webhook to {}: {}", category_str, label, event.title);
+            return Ok(Outcome::Sent);
+        };
+        
+        let entry = OutboxEntry::new(&event.id, &category_str, &event.title, &destination.key, payload);
+        
+        // Older deliveries to the same webhook have to go out first
+        if outbox.has_pending(&destination.key).await? {
+            outbox.enqueue(&entry).await?;
+            return Ok(Outcome::Queued);
+        }
+        
+        match self.post(&settings.client, &target.url, &entry.payload, target.secret.as_deref()).await {
+            Ok(()) => {
+                log::info!("Sent {} webhook to {}: {}", category_str, label, event.title);
+                Ok(Outcome::Sent)
+            }
+            Err(e) if e.is_permanent() => {
+                // Queueing would only hide a broken webhook
+                log::error!("{} webhook to {} rejected '{}': {}", category_str, label, event.title, e);
+                Err(anyhow::Error::new(e).context(format!("Failed to send {} webhook", category_str)))
+            }
+            Err(e) => {
+                log::warn!("Failed to send {} webhook to {} '{}': {}", category_str, label, event.title, e);
+                
+                let mut entry = entry;
+                entry.record_failure(&e.into());
+                outbox.enqueue(&entry).await?;
+                Ok(Outcome::Queued)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_server::{response, serve};
+    
+    /// Notifier recording the titles of the events it gets, failing for
+    /// titles starting with "fail"
+    #[derive(Default)]
+    struct Recorder {
+        titles: Mutex<Vec<String>>,
+    }
+    
+    impl Notifier for Recorder {
+        fn notify<'a>(&'a self, event: &'a Event) -> NotifyFuture<'a> {
+            Box::pin(async move {
+                self.titles.lock().unwrap().push(event.title.clone());
+                if event.title.starts_with("fail") {
+                    anyhow::bail!("delivery failed");
+                }
+                Ok(())
+            })
+        }
+    }
+    
+    #[test]
+    fn blocking_notifier_needs_a_runtime() {
+        assert!(BlockingNotifier::new(Arc::new(Recorder::default())).is_err());
+    }
+    
+    #[test]
+    fn blocking_notifier_delivers_from_plain_threads() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let notifier = {
+            let _guard = runtime.enter();
+            BlockingNotifier::new(recorder.clone()).unwrap()
+        };
+        
+        let results = std::thread::spawn(move || {
+            ["sent", "failed"].map(|title| notifier.notify(&Event::new(EventCategory::USB, "test", title, "message")))
+        }).join().unwrap();
+        
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), "delivery failed");
+        assert_eq!(*recorder.titles.lock().unwrap(), ["sent", "failed"]);
+    }
+    
+    fn sender() -> WebhookSender {
+        WebhookSender::new("test".to_string(), Router::default()).unwrap()
+    }
+    
+    async fn post(sender: &WebhookSender, url: &str) -> std::result::Result<(), DeliveryError> {
+        let payload = Payload::json(&serde_json::json!({ "content": "test" })).unwrap();
+        sender.post(&sender.settings().client, url, &payload, None).await
+    }
+    
+    #[tokio::test]
+    async fn classifies_responses() {
+        let (url, server) = serve(vec![
+            response("204 No Content", &[], ""),
+            response("500 Internal Server Error", &[], "try again"),
+            response("408 Request Timeout", &[], ""),
+            response("404 Not Found", &[], "Unknown Webhook"),
+        ]).await;
+        let sender = sender();
+        
+        assert!(post(&sender, &url).await.is_ok());
+        
+        let server_error = post(&sender, &url).await.unwrap_err();
+        assert!(matches!(&server_error, DeliveryError::Server { status, body } if status.as_u16() == 500 && body == "try again"), "{:?}", server_error);
+        assert!(!server_error.is_permanent());
+        
+        assert!(matches!(post(&sender, &url).await, Err(DeliveryError::Server { .. })));
+        
+        let rejected = post(&sender, &url).await.unwrap_err();
+        assert!(matches!(&rejected, DeliveryError::Rejected { status, .. } if status.as_u16() == 404), "{:?}", rejected);
+        assert!(rejected.is_permanent());
+        
+        assert_eq!(server.await.unwrap().len(), 4);
+    }
+    
+    #[tokio::test]
+    async fn truncates_long_error_bodies() {
+        let (url, _server) = serve(vec![response("400 Bad Request", &[], &"é".repeat(400))]).await;
+        
+        match post(&sender(), &url).await {
+            Err(DeliveryError::Rejected { body, .. }) => assert_eq!(body, "é".repeat(250)),
+            other => panic!("{:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn retries_short_rate_limits_in_place() {
+        let (url, server) = serve(vec![
+            response("429 Too Many Requests", &[("Retry-After", "0")], ""),
+            response("200 OK", &[], ""),
+        ]).await;
+        
+        assert!(post(&sender(), &url).await.is_ok());
+        assert_eq!(server.await.unwrap().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn leaves_long_rate_limits_to_the_outbox() {
+        let (url, server) = serve(vec![response("429 Too Many Requests", &[("Retry-After", "120")], "")]).await;
+        let sender = sender();
+        
+        let error = post(&sender, &url).await.unwrap_err();
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(120)));
+        assert!(!error.is_permanent());
+        assert_eq!(server.await.unwrap().len(), 1);
+        
+        // Later requests to the same URL wait for the limit
+        let until = sender.rate_limits.lock().unwrap()[&url];
+        assert!(until > Instant::now() + Duration::from_secs(100));
+    }
+    
+    #[tokio::test]
+    async fn fans_out_to_every_destination() {
+        let (ok_url, ok_server) = serve(vec![response("204 No Content", &[], "")]).await;
+        let (failing_url, failing_server) = serve(vec![response("404 Not Found", &[], "Unknown Webhook")]).await;
+        
+        let config: crate::Config = serde_json::from_value(serde_json::json!({
+            "destinations": {
+                "ok": ok_url,
+                "failing": { "url": failing_url, "name": "Failing hook" },
+                "filtered": { "url": "http://127.0.0.1:9/hook", "exclude_kinds": ["usb_*"] },
+            },
+            "routes": [{ "match": { "category": "usb" }, "to": ["ok", "failing", "filtered"] }],
+        })).unwrap();
+        let sender = WebhookSender::from_config(&config).unwrap();
+        
+        let report = sender.send_event(&Event::new(EventCategory::USB, "usb_connected", "USB connected", "A drive")).await;
+        let outcomes: Vec<_> = report.outcomes.iter()
+            .map(|outcome| (outcome.destination.as_str(), outcome.outcome.to_string()))
+            .collect();
+        
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes.contains(&("ok", "sent".to_string())), "{:?}", outcomes);
+        assert!(outcomes.contains(&("filtered", Outcome::Skipped.to_string())), "{:?}", outcomes);
+        assert!(outcomes.iter().any(|(destination, outcome)| *destination == "Failing hook" && outcome.contains("404")), "{:?}", outcomes);
+        assert!(!report.is_success());
+        
+        assert_eq!(ok_server.await.unwrap().len(), 1);
+        assert_eq!(failing_server.await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn keeps_urls_out_of_transport_errors() {
+        // Nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/hook?token=secret-token", port);
+        
+        let config: crate::Config = serde_json::from_value(serde_json::json!({
+            "destinations": { "refused": url },
+            "routes": [{ "match": { "category": "usb" }, "to": "refused" }],
+        })).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let sender = WebhookSender::from_config(&config).unwrap()
+            .with_outbox(Outbox::open(dir.path()).await.unwrap());
+        
+        let report = sender.send_event(&Event::new(EventCategory::USB, "usb_connected", "USB connected", "A drive")).await;
+        assert!(matches!(report.outcomes[0].outcome, Outcome::Queued), "{}", report.outcomes[0].outcome);
+        
+        let error = post(&sender, &url).await.unwrap_err();
+        assert!(matches!(error, DeliveryError::Transport(_)), "{:?}", error);
+        assert!(!format!("{:?}", error).contains("secret-token"), "{:?}", error);
+        
+        let stored = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+            .find(|contents| contents.contains("last_error"))
+            .unwrap();
+        let entry: OutboxEntry = serde_json::from_str(&stored).unwrap();
+        let last_error = entry.last_error.unwrap();
+        assert!(!last_error.is_empty());
+        assert!(!last_error.contains("127.0.0.1") && !last_error.contains("secret-token"), "{}", last_error);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Result;
+use super::{Event, Payload, Severity};
+
+/// Longest RFC 2047 encoded word, including its delimiters
+const MAX_ENCODED_WORD: usize = 75;
+
+/// Render an event for an ntfy topic URL.
+///
+/// ntfy takes the message as a plain text body and the title, tags and
+/// priority from request headers. Header values must be ASCII, so a title
+/// with other characters is sent as RFC 2047 encoded words, which ntfy
+/// decodes.
+pub fn render(event: &Event, device_name: &str) -> Result<Payload> {
+    let mut body = event.message.clone();
+    
+    if !event.fields.is_empty() {
+        body.push('\n');
+        for (name, value) in &event.fields {
+            body.push_str(&format!("\n{}: {}", name, value));
+        }
+    }
+    
+    let tags = match event.category.as_str() {
+        "system" => "computer",
+        "usb" => "electric_plug",
+        "idle" => "zzz",
+        _ => "bell",
+    };
+    
+    let mut headers = vec![
+        ("Title".to_string(), encode_header(&format!("{} ({})", event.title, device_name))),
+        ("Tags".to_string(), tags.to_string()),
+    ];
+    
+    // Informational events keep the topic's default priority
+    match event.severity {
+        Severity::Info => {}
+        Severity::Warning => headers.push(("Priority".to_string(), "high".to_string())),
+        Severity::Critical => headers.push(("Priority".to_string(), "urgent".to_string())),
+    }
+    
+    Ok(Payload {
+        content_type: "text/plain; charset=utf-8".to_string(),
+        headers,
+        body,
+    })
+}
+
+/// `text` as is when it is printable ASCII, otherwise as RFC 2047 "Q"
+/// encoded words
+fn encode_header(text: &str) -> String {
+    if text.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return text.to_string();
+    }
+    
+    const PREFIX: &str = "=?UTF-8?Q?";
+    const SUFFIX: &str = "?=";
+    
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        let encoded = match c {
+            ' ' => "_".to_string(),
+            c if c.is_ascii_alphanumeric() || "!*+-/".contains(c) => c.to_string(),
+            c => c.encode_utf8(&mut [0; 4]).bytes().map(|byte| format!("={:02X}", byte)).collect(),
+        };
+        
+        // Characters are never split across words
+        if PREFIX.len() + word.len() + encoded.len() + SUFFIX.len() > MAX_ENCODED_WORD {
+            words.push(format!("{}{}{}", PREFIX, word, SUFFIX));
+            word.clear();
+        }
+        word.push_str(&encoded);
+    }
+    words.push(format!("{}{}{}", PREFIX, word, SUFFIX));
+    
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::EventCategory;
+    
+    #[test]
+    fn leaves_ascii_titles_alone() {
+        assert_eq!(encode_header("System Started (office-pc)"), "System Started (office-pc)");
+    }
+    
+    #[test]
+    fn encodes_non_ascii_titles() {
+        assert_eq!(encode_header("USB (Büro)"), "=?UTF-8?Q?USB_=28B=C3=BCro=29?=");
+        assert_eq!(encode_header("a=b?_ 😀"), "=?UTF-8?Q?a=3Db=3F=5F_=F0=9F=98=80?=");
+    }
+    
+    #[test]
+    fn splits_long_titles_into_words() {
+        let encoded = encode_header(&"é".repeat(40));
+        
+        for word in encoded.split(' ') {
+            assert!(word.len() <= MAX_ENCODED_WORD, "{}", word);
+            assert!(word.starts_with("=?UTF-8?Q?") && word.ends_with("?="), "{}", word);
+            // Whole characters only
+            assert_eq!(word.matches("=C3=A9").count() * 6 + 12, word.len(), "{}", word);
+        }
+        assert_eq!(encoded.matches("=C3=A9").count(), 40);
+    }
+    
+    #[test]
+    fn renders_valid_header_values() {
+        let event = Event::new(EventCategory::USB, "usb_connected", "USB-Gerät verbunden", "Ein Gerät");
+        let payload = render(&event, "Büro-PC").unwrap();
+        
+        for (name, value) in &payload.headers {
+            assert!(reqwest::header::HeaderValue::from_str(value).is_ok(), "{}: {}", name, value);
+        }
+        assert_eq!(payload.body, "Ein Gerät");
+    }
+}
@@ -0,0 +1,539 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use super::error::{self, DeliveryError};
+use super::Payload;
+use crate::utils;
+
+/// Delay before the first retry; doubles with every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Upper bound for the retry delay
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Entries older than this are dropped instead of retried
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Maximum number of queued deliveries; the oldest are dropped beyond this
+const MAX_ENTRIES: usize = 1000;
+
+/// A webhook delivery waiting to be retried
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub event_id: String,
+    pub category: String,
+    pub title: String,
+    /// Key of the destination in the config, which supplies the URL and
+    /// signing secret when the entry is delivered
+    pub destination: String,
+    pub payload: Payload,
+    pub created_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    pub fn new(event_id: &str, category: &str, title: &str, destination: &str, payload: Payload) -> Self {
+        let now = Utc::now();
+        
+        Self {
+            event_id: event_id.to_string(),
+            category: category.to_string(),
+            title: title.to_string(),
+            destination: destination.to_string(),
+            payload,
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+        }
+    }
+    
+    /// Record a failed attempt and schedule the next one, honouring any
+    /// delay the endpoint asked for
+    pub fn record_failure(&mut self, error: &anyhow::Error) {
+        self.attempts += 1;
+        self.last_error = Some(format!("{:#}", error));
+        
+        let delay = error.downcast_ref::<DeliveryError>()
+            .and_then(DeliveryError::retry_after)
+            .unwrap_or_else(|| backoff(self.attempts));
+        self.next_attempt_at = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+    }
+}
+
+/// Outcome of one pass over the outbox
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlushStats {
+    pub delivered: usize,
+    pub failed: usize,
+    pub rejected: usize,
+    pub expired: usize,
+    pub remaining: usize,
+}
+
+/// Durable queue of undelivered webhook requests.
+///
+/// Each entry is a JSON file whose name carries a sequence number, so the
+/// directory listing gives the delivery order. Entries for the same
+/// destination are always delivered in order: once one fails, the later ones
+/// wait for it.
+pub struct Outbox {
+    dir: PathBuf,
+    /// Held while the queue files change
+    state: Mutex<State>,
+    /// Held for a whole flush, so two flushes never deliver an entry twice
+    flushing: Mutex<()>,
+}
+
+/// In-memory index of the queue files
+struct State {
+    next_seq: u64,
+    /// Schedule of each queued entry, by sequence number
+    queued: BTreeMap<u64, Queued>,
+}
+
+/// What the index keeps of an entry, enough to schedule it without reading
+/// its file
+struct Queued {
+    destination: String,
+    created_at: DateTime<Utc>,
+    next_attempt_at: DateTime<Utc>,
+}
+
+impl From<&OutboxEntry> for Queued {
+    fn from(entry: &OutboxEntry) -> Self {
+        Self {
+            destination: entry.destination.clone(),
+            created_at: entry.created_at,
+            next_attempt_at: entry.next_attempt_at,
+        }
+    }
+}
+
+impl State {
+    /// Pick the entries that are due at `now`, oldest first, and take the
+    /// expired ones out of the index
+    fn schedule(&mut self, now: DateTime<Utc>) -> Schedule {
+        let max_age = chrono::Duration::from_std(MAX_AGE).unwrap_or_default();
+        let mut schedule = Schedule::default();
+        
+        for (seq, queued) in &self.queued {
+            if now - queued.created_at > max_age {
+                schedule.expired.push(*seq);
+                schedule.stats.expired += 1;
+            } else if schedule.blocked_destinations.contains(&queued.destination) || queued.next_attempt_at > now {
+                schedule.blocked_destinations.insert(queued.destination.clone());
+                schedule.stats.remaining += 1;
+            } else {
+                schedule.due.push((*seq, queued.destination.clone()));
+            }
+        }
+        
+        for seq in &schedule.expired {
+            self.queued.remove(seq);
+        }
+        
+        schedule
+    }
+}
+
+impl Outbox {
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        
+        let state = blocking({
+            let dir = dir.clone();
+            move || {
+                // Queued payloads may carry tokens
+                utils::create_private_dir(&dir)
+                    .with_context(|| format!("Failed to create outbox directory {:?}", dir))?;
+                
+                let entries = list_entries(&dir)?;
+                let next_seq = entries.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+                
+                let mut queued = BTreeMap::new();
+                for (seq, path) in entries {
+                    if let Some(entry) = read_entry(&path)? {
+                        queued.insert(seq, Queued::from(&entry));
+                    }
+                }
+                
+                Ok(State { next_seq, queued })
+            }
+        }).await?;
+        
+        Ok(Self {
+            dir,
+            state: Mutex::new(state),
+            flushing: Mutex::new(()),
+        })
+    }
+    
+    /// Default outbox location next to the config file
+    pub fn default_dir() -> Result<PathBuf> {
+        Ok(crate::config::get_config_dir()?.join("outbox"))
+    }
+    
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", seq))
+    }
+    
+    /// Append an entry to the end of the queue
+    pub async fn enqueue(&self, entry: &OutboxEntry) -> Result<()> {
+        let mut state = self.state.lock().await;
+        
+        let mut dropped = Vec::new();
+        while state.queued.len() >= MAX_ENTRIES {
+            let Some((seq, _)) = state.queued.pop_first() else {
+                break;
+            };
+            dropped.push(self.entry_path(seq));
+        }
+        
+        let seq = state.next_seq;
+        let path = self.entry_path(seq);
+        let contents = serde_json::to_vec_pretty(entry)?;
+        blocking(move || {
+            for path in &dropped {
+                log::warn!("Outbox full, dropping oldest entry {:?}", path);
+                remove_entry(path);
+            }
+            
+            utils::write_atomic(&path, &contents).context("Failed to write outbox entry")
+        }).await?;
+        state.next_seq += 1;
+        state.queued.insert(seq, Queued::from(entry));
+        
+        log::info!("Queued {} webhook for retry: {}", entry.category, entry.title);
+        Ok(())
+    }
+    
+    /// Whether deliveries to `destination` are still waiting in the queue
+    pub async fn has_pending(&self, destination: &str) -> Result<bool> {
+        Ok(self.state.lock().await.queued.values().any(|queued| queued.destination == destination))
+    }
+    
+    pub async fn len(&self) -> Result<usize> {
+        Ok(self.state.lock().await.queued.len())
+    }
+    
+    pub async fn is_empty(&self) -> Result<bool> {
+        Ok(self.len().await? == 0)
+    }
+    
+    /// Retry every entry that is due, oldest first.
+    ///
+    /// Delivered and expired entries are removed. After a failure, the
+    /// remaining entries for the same destination are skipped to keep their
+    /// order.
+    /// The queue stays open for new entries while deliveries are under way.
+    pub async fn flush<F, Fut>(&self, mut deliver: F) -> Result<FlushStats>
+    where
+        F: FnMut(OutboxEntry) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let _flushing = self.flushing.lock().await;
+        
+        let Schedule { due, expired, mut blocked_destinations, mut stats } = self.state.lock().await.schedule(Utc::now());
+        
+        if !expired.is_empty() {
+            let paths: Vec<_> = expired.iter().map(|seq| self.entry_path(*seq)).collect();
+            blocking(move || {
+                for path in &paths {
+                    log::warn!("Dropping outbox entry {:?}, it is older than {} days", path, MAX_AGE.as_secs() / (24 * 60 * 60));
+                    remove_entry(path);
+                }
+                Ok(())
+            }).await?;
+        }
+        
+        for (seq, destination) in due {
+            if blocked_destinations.contains(&destination) {
+                stats.remaining += 1;
+                continue;
+            }
+            
+            let path = self.entry_path(seq);
+            let mut entry = match blocking({
+                let path = path.clone();
+                move || read_entry(&path)
+            }).await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    self.state.lock().await.queued.remove(&seq);
+                    continue;
+                }
+                // Dropped by `enqueue` since the schedule was made
+                Err(_) if !self.state.lock().await.queued.contains_key(&seq) => continue,
+                Err(e) => return Err(e),
+            };
+            
+            let result = deliver(entry.clone()).await;
+            
+            let mut state = self.state.lock().await;
+            // Dropped by `enqueue` while it was being delivered
+            if !state.queued.contains_key(&seq) {
+                continue;
+            }
+            
+            match result {
+                Ok(()) => {
+                    log::info!("Delivered queued {} webhook: {}", entry.category, entry.title);
+                    state.queued.remove(&seq);
+                    blocking(move || {
+                        remove_entry(&path);
+                        Ok(())
+                    }).await?;
+                    stats.delivered += 1;
+                }
+                Err(e) if error::is_permanent(&e) => {
+                    log::error!("Dropping queued {} webhook '{}': {:#}", entry.category, entry.title, e);
+                    state.queued.remove(&seq);
+                    blocking(move || {
+                        remove_entry(&path);
+                        Ok(())
+                    }).await?;
+                    stats.rejected += 1;
+                }
+                Err(e) => {
+                    entry.record_failure(&e);
+                    log::warn!(
+                        "Retry {} of {} webhook '{}' failed, next attempt at {}: {:#}",
+                        entry.attempts, entry.category, entry.title, entry.next_attempt_at, e
+                    );
+                    
+                    let contents = serde_json::to_vec_pretty(&entry)?;
+                    blocking(move || utils::write_atomic(&path, &contents).context("Failed to update outbox entry")).await?;
+                    state.queued.insert(seq, Queued::from(&entry));
+                    
+                    blocked_destinations.insert(entry.destination.clone());
+                    stats.failed += 1;
+                    stats.remaining += 1;
+                }
+            }
+        }
+        
+        Ok(stats)
+    }
+}
+
+/// Entries picked for one flush
+#[derive(Default)]
+struct Schedule {
+    /// Sequence numbers and destinations of the entries to deliver, oldest
+    /// first
+    due: Vec<(u64, String)>,
+    /// Expired entries, no longer in the index
+    expired: Vec<u64>,
+    /// Destinations with an older entry that is not due yet
+    blocked_destinations: HashSet<String>,
+    /// Expired and waiting entries
+    stats: FlushStats,
+}
+
+/// Run file system work off the async runtime threads
+async fn blocking<T, F>(work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await.context("Outbox task failed")?
+}
+
+fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Queue files sorted by sequence number
+fn list_entries(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut entries = Vec::new();
+    
+    for dir_entry in fs::read_dir(dir).with_context(|| format!("Failed to read outbox directory {:?}", dir))? {
+        let path = dir_entry?.path();
+        
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        
+        if let Some(seq) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+            entries.push((seq, path));
+        }
+    }
+    
+    entries.sort_by_key(|(seq, _)| *seq);
+    Ok(entries)
+}
+
+/// Read an entry, setting unreadable files aside instead of failing the queue
+fn read_entry(path: &Path) -> Result<Option<OutboxEntry>> {
+    let contents = fs::read(path)
+        .with_context(|| format!("Failed to read outbox entry {:?}", path))?;
+    
+    match serde_json::from_slice(&contents) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e) => {
+            log::error!("Corrupt outbox entry {:?}: {}", path, e);
+            
+            let corrupt_path = path.with_extension("corrupt");
+            if let Err(e) = fs::rename(path, &corrupt_path) {
+                log::error!("Failed to move corrupt outbox entry aside: {}", e);
+            }
+            
+            Ok(None)
+        }
+    }
+}
+
+fn remove_entry(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        log::error!("Failed to remove outbox entry {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    
+    fn entry(event_id: &str, destination: &str) -> OutboxEntry {
+        OutboxEntry::new(event_id, "usb", "USB connected", destination, Payload::json(&event_id).unwrap())
+    }
+    
+    /// Flush with a delivery that fails for the given event ids, returning
+    /// the ids it was called with
+    async fn flush(outbox: &Outbox, failing: &[&str]) -> (FlushStats, Vec<String>) {
+        let attempted = StdMutex::new(Vec::new());
+        
+        let stats = outbox.flush(|entry| {
+            attempted.lock().unwrap().push(entry.event_id.clone());
+            let fails = failing.contains(&entry.event_id.as_str());
+            async move {
+                if fails {
+                    anyhow::bail!("connection refused");
+                }
+                Ok(())
+            }
+        }).await.unwrap();
+        
+        (stats, attempted.into_inner().unwrap())
+    }
+    
+    #[tokio::test]
+    async fn delivers_in_queue_order_across_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        for (event_id, destination) in [("1", "a"), ("2", "b"), ("3", "a")] {
+            outbox.enqueue(&entry(event_id, destination)).await.unwrap();
+        }
+        drop(outbox);
+        
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        assert_eq!(outbox.len().await.unwrap(), 3);
+        assert!(outbox.has_pending("b").await.unwrap());
+        
+        let (stats, attempted) = flush(&outbox, &[]).await;
+        assert_eq!(attempted, ["1", "2", "3"]);
+        assert_eq!(stats.delivered, 3);
+        assert!(outbox.is_empty().await.unwrap());
+        assert!(list_entries(dir.path()).unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn backs_off_after_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        outbox.enqueue(&entry("1", "a")).await.unwrap();
+        
+        let (stats, attempted) = flush(&outbox, &["1"]).await;
+        assert_eq!(attempted, ["1"]);
+        assert_eq!((stats.failed, stats.remaining), (1, 1));
+        
+        // Not due again until the backoff has passed, also after reopening
+        let (stats, attempted) = flush(&outbox, &[]).await;
+        assert!(attempted.is_empty());
+        assert_eq!(stats.remaining, 1);
+        
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        assert!(flush(&outbox, &[]).await.1.is_empty());
+        
+        let (_, path) = &list_entries(dir.path()).unwrap()[0];
+        let stored = read_entry(path).unwrap().unwrap();
+        assert_eq!(stored.attempts, 1);
+        assert_eq!(stored.last_error.as_deref(), Some("connection refused"));
+        assert!(stored.next_attempt_at > Utc::now() + chrono::Duration::seconds(20));
+    }
+    
+    #[test]
+    fn doubles_backoff_up_to_the_maximum() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(3), INITIAL_BACKOFF * 4);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+    
+    #[tokio::test]
+    async fn drops_expired_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        
+        let mut expired = entry("1", "a");
+        expired.created_at -= chrono::Duration::from_std(MAX_AGE).unwrap() + chrono::Duration::minutes(1);
+        outbox.enqueue(&expired).await.unwrap();
+        outbox.enqueue(&entry("2", "a")).await.unwrap();
+        
+        let (stats, attempted) = flush(&outbox, &[]).await;
+        assert_eq!(attempted, ["2"]);
+        assert_eq!((stats.expired, stats.delivered), (1, 1));
+        assert!(list_entries(dir.path()).unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn holds_back_later_entries_of_a_failing_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        for (event_id, destination) in [("1", "a"), ("2", "b"), ("3", "a")] {
+            outbox.enqueue(&entry(event_id, destination)).await.unwrap();
+        }
+        
+        let (stats, attempted) = flush(&outbox, &["1"]).await;
+        assert_eq!(attempted, ["1", "2"]);
+        assert_eq!((stats.delivered, stats.failed, stats.remaining), (1, 1, 2));
+        
+        // The waiting entry still blocks the later one
+        let (stats, attempted) = flush(&outbox, &[]).await;
+        assert!(attempted.is_empty());
+        assert_eq!(stats.remaining, 2);
+        assert!(outbox.has_pending("a").await.unwrap());
+        assert!(!outbox.has_pending("b").await.unwrap());
+    }
+    
+    #[tokio::test]
+    async fn drops_the_oldest_entries_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path()).await.unwrap();
+        for i in 0..MAX_ENTRIES + 2 {
+            outbox.enqueue(&entry(&i.to_string(), "a")).await.unwrap();
+        }
+        
+        assert_eq!(outbox.len().await.unwrap(), MAX_ENTRIES);
+        
+        let (_, attempted) = flush(&outbox, &[]).await;
+        assert_eq!(attempted.len(), MAX_ENTRIES);
+        assert_eq!(attempted[0], "2");
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+use std::sync::Arc;
+use crate::config::{Config, EventMatch, WebhookTarget};
+use super::Event;
+
+/// A configured webhook and the key it is configured under
+#[derive(Debug)]
+pub struct Destination {
+    /// Key in the `destinations` table, which identifies queued deliveries
+    pub key: String,
+    pub target: WebhookTarget,
+}
+
+impl Destination {
+    /// Name for logs and delivery reports: the configured `name`, else the
+    /// key, so tokens in the URL stay out
+    pub fn label(&self) -> &str {
+        self.target.name.as_deref().unwrap_or(&self.key)
+    }
+}
+
+/// Destinations that receive the events matched by one rule
+#[derive(Debug, Clone)]
+struct Route {
+    matcher: EventMatch,
+    targets: Vec<Arc<Destination>>,
+}
+
+/// Selects the destinations of each event from the routing rules.
+///
+/// Routes apply in config order. Every matching rule applies, but an event
+/// reaches each destination only once.
+#[derive(Debug, Default, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    destinations: Vec<Arc<Destination>>,
+}
+
+impl Router {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut routes = Vec::new();
+        
+        // Shared so that the same destination can be recognised across rules
+        let destinations: Vec<Arc<Destination>> = config.destinations.iter()
+            .map(|(key, target)| Arc::new(Destination { key: key.clone(), target: target.clone() }))
+            .collect();
+        
+        for (index, rule) in config.routes.iter().enumerate() {
+            let mut targets = Vec::new();
+            
+            for name in &rule.to {
+                let target = destinations.iter()
+                    .find(|destination| destination.key == *name)
+                    .ok_or_else(|| anyhow::anyhow!("routes[{}] sends to unknown destination '{}'", index, name))?;
+                targets.push(Arc::clone(target));
+            }
+            
+            routes.push(Route {
+                matcher: rule.matcher.clone(),
+                targets,
+            });
+        }
+        
+        for destination in &destinations {
+            if !routes.iter().any(|route| route.targets.iter().any(|t| Arc::ptr_eq(t, destination))) {
+                log::warn!("Destination '{}' is not used by any route", destination.key);
+            }
+        }
+        
+        Ok(Self { routes, destinations })
+    }
+    
+    /// Destinations `event` is routed to, without duplicates.
+    ///
+    /// Destinations posting the same request to the same webhook count as
+    /// one, unless the filters of one of them exclude the event.
+    pub fn targets_for(&self, event: &Event) -> Vec<Arc<Destination>> {
+        let mut targets: Vec<Arc<Destination>> = Vec::new();
+        
+        for route in self.routes.iter().filter(|route| route.matcher.matches(event)) {
+            for target in &route.targets {
+                let duplicate = targets.iter().any(|t| {
+                    Arc::ptr_eq(t, target)
+                        || (same_request(&t.target, &target.target) && t.target.accepts(event) && target.target.accepts(event))
+                });
+                
+                if !duplicate {
+                    targets.push(Arc::clone(target));
+                }
+            }
+        }
+        
+        targets
+    }
+    
+    /// The destination configured under `key`
+    pub fn destination(&self, key: &str) -> Option<&Destination> {
+        self.destinations.iter()
+            .find(|destination| destination.key == key)
+            .map(|destination| destination.as_ref())
+    }
+}
+
+/// Whether two destinations send identical requests to the same webhook
+fn same_request(a: &WebhookTarget, b: &WebhookTarget) -> bool {
+    a.url.expose() == b.url.expose()
+        && a.format == b.format
+        && a.secret.as_ref().map(|secret| secret.expose()) == b.secret.as_ref().map(|secret| secret.expose())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::{EventCategory, Severity};
+    use serde_json::json;
+    
+    fn router(config: serde_json::Value) -> Router {
+        Router::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+    
+    fn keys(router: &Router, event: &Event) -> Vec<String> {
+        router.targets_for(event).iter().map(|destination| destination.key.clone()).collect()
+    }
+    
+    fn event(category: EventCategory) -> Event {
+        Event::new(category, "test", "Test", "Test event")
+    }
+    
+    #[test]
+    fn routes_by_category() {
+        let router = router(json!({
+            "destinations": { "a": "https://example.com/a", "b": "https://example.com/b" },
+            "routes": [
+                { "match": { "category": "system" }, "to": "a" },
+                { "match": { "category": ["usb", "battery"] }, "to": ["a", "b"] },
+            ],
+        }));
+        
+        assert_eq!(keys(&router, &event(EventCategory::SYSTEM)), ["a"]);
+        assert_eq!(keys(&router, &event(EventCategory::USB)), ["a", "b"]);
+        assert_eq!(keys(&router, &event("battery".parse().unwrap())), ["a", "b"]);
+        assert!(keys(&router, &event(EventCategory::IDLE)).is_empty());
+    }
+    
+    #[test]
+    fn delivers_once_per_webhook() {
+        let router = router(json!({
+            "destinations": {
+                "a": "https://example.com/hook",
+                "b": "https://example.com/hook",
+                "c": { "url": "https://example.com/hook", "format": "json" },
+                "d": { "url": "https://example.com/hook", "secret": "key" },
+            },
+            "routes": [
+                { "match": { "category": "system" }, "to": ["a", "b"] },
+                { "match": {}, "to": ["a", "c", "d"] },
+            ],
+        }));
+        
+        // b is the same request as a, c differs in format and d in secret
+        assert_eq!(keys(&router, &event(EventCategory::SYSTEM)), ["a", "c", "d"]);
+    }
+    
+    #[test]
+    fn keeps_duplicates_whose_filters_differ() {
+        let router = router(json!({
+            "destinations": {
+                "pager": { "url": "https://example.com/hook", "min_severity": "critical" },
+                "all": "https://example.com/hook",
+            },
+            "routes": [{ "match": {}, "to": ["pager", "all"] }],
+        }));
+        
+        let info = event(EventCategory::USB);
+        let targets = router.targets_for(&info);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets.iter().filter(|destination| destination.target.accepts(&info)).count(), 1);
+        
+        let critical = event(EventCategory::USB).with_severity(Severity::Critical);
+        assert_eq!(keys(&router, &critical), ["pager"]);
+    }
+    
+    #[test]
+    fn looks_up_destinations_by_key() {
+        let router = router(json!({
+            "destinations": {
+                "a": { "url": "https://example.com/hook", "secret": "key-a" },
+                "b": { "url": "https://example.com/hook", "secret": "key-b" },
+            },
+            "routes": [{ "match": {}, "to": ["a", "b"] }],
+        }));
+        
+        assert_eq!(router.destination("b").unwrap().target.secret.as_deref(), Some("key-b"));
+        assert!(router.destination("c").is_none());
+    }
+    
+    #[test]
+    fn rejects_unknown_destinations() {
+        let config = serde_json::from_value(json!({
+            "destinations": { "a": "https://example.com/a" },
+            "routes": [{ "match": {}, "to": "b" }],
+        })).unwrap();
+        
+        assert!(Router::from_config(&config).unwrap_err().to_string().contains("unknown destination 'b'"));
+    }
+    
+    #[test]
+    fn labels_with_name_or_key() {
+        let router = router(json!({
+            "destinations": {
+                "alerts": "https://discord.com/api/webhooks/1/token",
+                "ops": { "url": "https://discord.com/api/webhooks/2/token", "name": "Ops channel" },
+            },
+            "routes": [{ "match": {}, "to": ["alerts", "ops"] }],
+        }));
+        
+        let labels: Vec<_> = router.targets_for(&event(EventCategory::SYSTEM)).iter()
+            .map(|destination| destination.label().to_string())
+            .collect();
+        assert_eq!(labels, ["alerts", "Ops channel"]);
+    }
+}
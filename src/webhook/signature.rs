@@ -0,0 +1,154 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Header carrying the signature of requests to webhooks with a `secret`.
+///
+/// The value is `t=<unix timestamp>,v1=<hex HMAC-SHA256>`, where the MAC is
+/// computed over `<timestamp>.<body>`, so a captured request cannot be
+/// replayed once the timestamp falls outside the receiver's tolerance.
+pub const SIGNATURE_HEADER: &str = "X-RAA-Signature";
+
+/// Maximum clock difference accepted by `verify`
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a signature was not accepted
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("malformed signature header")]
+    Malformed,
+    
+    #[error("signature timestamp is outside the allowed tolerance")]
+    Expired,
+    
+    #[error("signature does not match the request body")]
+    Mismatch,
+}
+
+/// Header value signing `body` with `secret` at `timestamp` (Unix seconds)
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let digest = mac(secret, timestamp, body).finalize().into_bytes();
+    format!("t={},v1={}", timestamp, hex::encode(digest))
+}
+
+/// Check a signature header against the received body, using the current
+/// time and `tolerance` to reject stale or replayed requests
+pub fn verify(secret: &[u8], header: &str, body: &[u8], tolerance: Duration) -> Result<(), SignatureError> {
+    verify_at(secret, header, body, tolerance, chrono::Utc::now().timestamp())
+}
+
+/// Like `verify`, with an explicit current time in Unix seconds
+pub fn verify_at(secret: &[u8], header: &str, body: &[u8], tolerance: Duration, now: i64) -> Result<(), SignatureError> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = Some(value.parse::<i64>().map_err(|_| SignatureError::Malformed)?),
+            Some(("v1", value)) => signatures.push(hex::decode(value).map_err(|_| SignatureError::Malformed)?),
+            // Unknown schemes are ignored so new ones can be added later
+            Some(_) => {}
+            None => return Err(SignatureError::Malformed),
+        }
+    }
+    
+    let timestamp = timestamp.ok_or(SignatureError::Malformed)?;
+    if signatures.is_empty() {
+        return Err(SignatureError::Malformed);
+    }
+    
+    if timestamp.abs_diff(now) > tolerance.as_secs() {
+        return Err(SignatureError::Expired);
+    }
+    
+    // verify_slice compares in constant time
+    let expected = mac(secret, timestamp, body);
+    if signatures.iter().any(|signature| expected.clone().verify_slice(signature).is_ok()) {
+        Ok(())
+    } else {
+        Err(SignatureError::Mismatch)
+    }
+}
+
+fn mac(secret: &[u8], timestamp: i64, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const SECRET: &[u8] = b"secret";
+    const BODY: &[u8] = br#"{"a":1}"#;
+    const NOW: i64 = 1_700_000_000;
+    
+    #[test]
+    fn signs_timestamp_and_body() {
+        // HMAC-SHA256 of `1700000000.{"a":1}` computed independently
+        assert_eq!(sign(SECRET, NOW, BODY), "t=1700000000,v1=49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686");
+    }
+    
+    #[test]
+    fn round_trip() {
+        let header = sign(SECRET, NOW, BODY);
+        
+        assert_eq!(verify_at(SECRET, &header, BODY, DEFAULT_TOLERANCE, NOW), Ok(()));
+        assert_eq!(verify(SECRET, &sign(SECRET, chrono::Utc::now().timestamp(), BODY), BODY, DEFAULT_TOLERANCE), Ok(()));
+    }
+    
+    #[test]
+    fn rejects_tampered_requests() {
+        let header = sign(SECRET, NOW, BODY);
+        
+        assert_eq!(verify_at(SECRET, &header, br#"{"a":2}"#, DEFAULT_TOLERANCE, NOW), Err(SignatureError::Mismatch));
+        
+        // Moving the timestamp invalidates the signature
+        let moved = header.replace("t=1700000000", "t=1700000001");
+        assert_eq!(verify_at(SECRET, &moved, BODY, DEFAULT_TOLERANCE, NOW), Err(SignatureError::Mismatch));
+    }
+    
+    #[test]
+    fn rejects_wrong_key() {
+        let header = sign(b"other", NOW, BODY);
+        
+        assert_eq!(verify_at(SECRET, &header, BODY, DEFAULT_TOLERANCE, NOW), Err(SignatureError::Mismatch));
+    }
+    
+    #[test]
+    fn rejects_timestamps_outside_tolerance() {
+        let tolerance = DEFAULT_TOLERANCE.as_secs() as i64;
+        
+        for skew in [-tolerance, tolerance] {
+            let header = sign(SECRET, NOW + skew, BODY);
+            assert_eq!(verify_at(SECRET, &header, BODY, DEFAULT_TOLERANCE, NOW), Ok(()), "skew {}", skew);
+        }
+        
+        for skew in [-tolerance - 1, tolerance + 1] {
+            let header = sign(SECRET, NOW + skew, BODY);
+            assert_eq!(verify_at(SECRET, &header, BODY, DEFAULT_TOLERANCE, NOW), Err(SignatureError::Expired), "skew {}", skew);
+        }
+    }
+    
+    #[test]
+    fn accepts_any_matching_signature() {
+        let valid = sign(SECRET, NOW, BODY);
+        let v1 = valid.split_once(",v1=").unwrap().1;
+        let header = format!("t={},v0=legacy,v1={},v1={}", NOW, "00".repeat(32), v1);
+        
+        assert_eq!(verify_at(SECRET, &header, BODY, DEFAULT_TOLERANCE, NOW), Ok(()));
+    }
+    
+    #[test]
+    fn rejects_malformed_headers() {
+        for header in ["", "t=1700000000", "v1=00", "t=soon,v1=00", "t=1700000000,v1=zz", "t=1700000000,garbage"] {
+            assert_eq!(verify_at(SECRET, header, BODY, DEFAULT_TOLERANCE, NOW), Err(SignatureError::Malformed), "{:?}", header);
+        }
+    }
+}
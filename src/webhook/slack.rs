@@ -0,0 +1,152 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use super::{Event, Payload};
+
+/// Slack allows at most 10 fields in a single section block
+const MAX_FIELDS_PER_SECTION: usize = 10;
+
+/// Slack rejects header blocks with more than 150 characters
+const MAX_HEADER_LENGTH: usize = 150;
+
+/// Render an event as a Slack incoming-webhook message using Block Kit.
+///
+/// The title becomes a header block, the message a section and the
+/// additional fields one or more field sections.
+pub fn render(event: &Event, device_name: &str) -> Result<Payload> {
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": {
+                "type": "plain_text",
+                "text": truncate(&event.title, MAX_HEADER_LENGTH),
+            },
+        }),
+        json!({
+            "type": "section",
+            "text": {
+                "type": "mrkdwn",
+                "text": escape(&event.message),
+            },
+        }),
+    ];
+    
+    for chunk in event.fields.chunks(MAX_FIELDS_PER_SECTION) {
+        let fields: Vec<Value> = chunk.iter()
+            .map(|(name, value)| json!({
+                "type": "mrkdwn",
+                "text": format!("*{}*\n{}", escape(name), escape(value)),
+            }))
+            .collect();
+        
+        blocks.push(json!({
+            "type": "section",
+            "fields": fields,
+        }));
+    }
+    
+    blocks.push(json!({
+        "type": "context",
+        "elements": [{
+            "type": "mrkdwn",
+            "text": format!(
+                "RAA v{} | Device: {} | <!date^{}^{{date_short_pretty}} {{time}}|{}>",
+                env!("CARGO_PKG_VERSION"),
+                escape(device_name),
+                event.timestamp.timestamp(),
+                event.timestamp.to_rfc3339(),
+            ),
+        }],
+    }));
+    
+    Payload::json(&json!({
+        // Used for notifications and clients that cannot show blocks
+        "text": escape(&format!("{}: {}", event.title, event.message)),
+        "blocks": blocks,
+    }))
+}
+
+/// Escape the characters Slack treats as control sequences in mrkdwn
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(max_chars - 1).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::EventCategory;
+    
+    fn render_value(event: &Event) -> Value {
+        let payload = render(event, "office-pc").unwrap();
+        assert_eq!(payload.content_type, "application/json");
+        serde_json::from_str(&payload.body).unwrap()
+    }
+    
+    #[test]
+    fn renders_header_message_fields_and_context() {
+        let event = Event::new(EventCategory::USB, "usb_connected", "USB connected", "A drive was connected")
+            .with_fields(vec![("Device".to_string(), "SanDisk".to_string())]);
+        let value = render_value(&event);
+        
+        assert_eq!(value["text"], "USB connected: A drive was connected");
+        
+        let blocks = value["blocks"].as_array().unwrap();
+        let types: Vec<_> = blocks.iter().map(|block| block["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["header", "section", "section", "context"]);
+        
+        assert_eq!(blocks[0]["text"], json!({ "type": "plain_text", "text": "USB connected" }));
+        assert_eq!(blocks[1]["text"], json!({ "type": "mrkdwn", "text": "A drive was connected" }));
+        assert_eq!(blocks[2]["fields"], json!([{ "type": "mrkdwn", "text": "*Device*\nSanDisk" }]));
+        
+        let context = blocks[3]["elements"][0]["text"].as_str().unwrap();
+        assert!(context.contains("Device: office-pc"), "{}", context);
+        assert!(context.contains(&format!("<!date^{}^", event.timestamp.timestamp())), "{}", context);
+    }
+    
+    #[test]
+    fn splits_fields_into_sections_of_ten() {
+        let fields = (0..12).map(|i| (format!("Field {}", i), i.to_string())).collect();
+        let event = Event::new(EventCategory::SYSTEM, "heartbeat", "Heartbeat", "Still running").with_fields(fields);
+        let value = render_value(&event);
+        
+        let sections: Vec<usize> = value["blocks"].as_array().unwrap().iter()
+            .filter_map(|block| block["fields"].as_array())
+            .map(Vec::len)
+            .collect();
+        assert_eq!(sections, [10, 2]);
+    }
+    
+    #[test]
+    fn escapes_mrkdwn_control_characters() {
+        let event = Event::new(EventCategory::SYSTEM, "test", "a < b", "<!channel> & more")
+            .with_fields(vec![("<name>".to_string(), "x > y".to_string())]);
+        let value = render_value(&event);
+        
+        assert_eq!(value["text"], "a &lt; b: &lt;!channel&gt; &amp; more");
+        assert_eq!(value["blocks"][1]["text"]["text"], "&lt;!channel&gt; &amp; more");
+        assert_eq!(value["blocks"][2]["fields"][0]["text"], "*&lt;name&gt;*\nx &gt; y");
+        // Plain text is shown as is
+        assert_eq!(value["blocks"][0]["text"]["text"], "a < b");
+    }
+    
+    #[test]
+    fn truncates_long_headers() {
+        let event = Event::new(EventCategory::SYSTEM, "test", &"é".repeat(200), "Message");
+        let value = render_value(&event);
+        
+        let header = value["blocks"][0]["text"]["text"].as_str().unwrap();
+        assert_eq!(header.chars().count(), MAX_HEADER_LENGTH);
+        assert!(header.ends_with('…'));
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use super::{Event, Payload, Severity};
+
+/// Render an event as a legacy Office 365 connector MessageCard.
+///
+/// The category colour becomes the card's theme colour and the additional
+/// fields are listed as facts.
+pub fn render_message_card(event: &Event, device_name: &str) -> Result<Payload> {
+    let facts: Vec<Value> = event.fields.iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+    
+    Payload::json(&json!({
+        "@type": "MessageCard",
+        "@context": "https://schema.org/extensions",
+        "themeColor": format!("{:06X}", event.color()),
+        "summary": event.title,
+        "sections": [{
+            "activityTitle": event.title,
+            "activitySubtitle": footer(event, device_name),
+            "text": event.message,
+            "facts": facts,
+            "markdown": true,
+        }],
+    }))
+}
+
+/// Render an event as an Adaptive Card message for Teams workflow webhooks.
+///
+/// Adaptive Cards only support a fixed set of styles, so the severity (or
+/// the category of informational events) is mapped to the closest container
+/// style and the fields to a FactSet.
+pub fn render_adaptive_card(event: &Event, device_name: &str) -> Result<Payload> {
+    let style = match (event.severity, event.category.as_str()) {
+        (Severity::Critical, _) => "attention",
+        (Severity::Warning, _) => "warning",
+        (Severity::Info, "system") => "accent",
+        (Severity::Info, "usb") => "attention",
+        (Severity::Info, "idle") => "warning",
+        (Severity::Info, _) => "default",
+    };
+    
+    let facts: Vec<Value> = event.fields.iter()
+        .map(|(name, value)| json!({ "title": name, "value": value }))
+        .collect();
+    
+    let mut body = vec![
+        json!({
+            "type": "Container",
+            "style": style,
+            "bleed": true,
+            "items": [{
+                "type": "TextBlock",
+                "text": event.title,
+                "weight": "Bolder",
+                "size": "Medium",
+                "wrap": true,
+            }],
+        }),
+        json!({
+            "type": "TextBlock",
+            "text": event.message,
+            "wrap": true,
+        }),
+    ];
+    
+    if !facts.is_empty() {
+        body.push(json!({
+            "type": "FactSet",
+            "facts": facts,
+        }));
+    }
+    
+    body.push(json!({
+        "type": "TextBlock",
+        "text": footer(event, device_name),
+        "isSubtle": true,
+        "size": "Small",
+        "wrap": true,
+    }));
+    
+    Payload::json(&json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "contentUrl": null,
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "msteams": { "width": "Full" },
+                "body": body,
+            },
+        }],
+    }))
+}
+
+fn footer(event: &Event, device_name: &str) -> String {
+    format!(
+        "RAA v{} | Device: {} | {}",
+        env!("CARGO_PKG_VERSION"),
+        device_name,
+        event.timestamp.to_rfc3339()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::EventCategory;
+    
+    fn to_value(payload: Payload) -> Value {
+        assert_eq!(payload.content_type, "application/json");
+        serde_json::from_str(&payload.body).unwrap()
+    }
+    
+    fn event() -> Event {
+        Event::new(EventCategory::USB, "usb_connected", "USB connected", "A drive was connected")
+            .with_fields(vec![("Device".to_string(), "SanDisk".to_string())])
+    }
+    
+    #[test]
+    fn renders_message_cards() {
+        let value = to_value(render_message_card(&event(), "office-pc").unwrap());
+        
+        assert_eq!(value["@type"], "MessageCard");
+        assert_eq!(value["themeColor"], "E74C3C");
+        assert_eq!(value["summary"], "USB connected");
+        
+        let section = &value["sections"][0];
+        assert_eq!(section["activityTitle"], "USB connected");
+        assert_eq!(section["text"], "A drive was connected");
+        assert_eq!(section["facts"], json!([{ "name": "Device", "value": "SanDisk" }]));
+        assert!(section["activitySubtitle"].as_str().unwrap().contains("Device: office-pc"));
+    }
+    
+    #[test]
+    fn renders_adaptive_cards() {
+        let value = to_value(render_adaptive_card(&event(), "office-pc").unwrap());
+        
+        assert_eq!(value["type"], "message");
+        let attachment = &value["attachments"][0];
+        assert_eq!(attachment["contentType"], "application/vnd.microsoft.card.adaptive");
+        assert_eq!(attachment["content"]["type"], "AdaptiveCard");
+        assert_eq!(attachment["content"]["version"], "1.4");
+        
+        let body = attachment["content"]["body"].as_array().unwrap();
+        let types: Vec<_> = body.iter().map(|item| item["type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["Container", "TextBlock", "FactSet", "TextBlock"]);
+        assert_eq!(body[0]["items"][0]["text"], "USB connected");
+        assert_eq!(body[1]["text"], "A drive was connected");
+        assert_eq!(body[2]["facts"], json!([{ "title": "Device", "value": "SanDisk" }]));
+        assert_eq!(body[3]["isSubtle"], true);
+    }
+    
+    #[test]
+    fn omits_empty_fact_sets() {
+        let event = Event::new(EventCategory::SYSTEM, "startup", "Started", "The agent started");
+        let value = to_value(render_adaptive_card(&event, "office-pc").unwrap());
+        
+        let body = value["attachments"][0]["content"]["body"].as_array().unwrap();
+        assert!(body.iter().all(|item| item["type"] != "FactSet"));
+    }
+    
+    #[test]
+    fn styles_adaptive_cards_by_severity_then_category() {
+        let cases = [
+            (EventCategory::SYSTEM, Severity::Info, "accent"),
+            (EventCategory::USB, Severity::Info, "attention"),
+            (EventCategory::IDLE, Severity::Info, "warning"),
+            ("custom".parse().unwrap(), Severity::Info, "default"),
+            (EventCategory::SYSTEM, Severity::Warning, "warning"),
+            (EventCategory::IDLE, Severity::Critical, "attention"),
+        ];
+        
+        for (category, severity, style) in cases {
+            let event = Event::new(category.clone(), "test", "Title", "Message").with_severity(severity);
+            let value = to_value(render_adaptive_card(&event, "office-pc").unwrap());
+            assert_eq!(value["attachments"][0]["content"]["body"][0]["style"], style, "{} {}", category, severity);
+        }
+    }
+}
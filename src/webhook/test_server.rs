@@ -0,0 +1,57 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Answer one connection per response, in order, with the raw HTTP
+/// `responses`. Returns the URL to post to and the requests received.
+pub(crate) async fn serve(responses: Vec<String>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook?token=secret-token", listener.local_addr().unwrap());
+    
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            requests.push(read_request(&mut stream).await);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+        
+        requests
+    });
+    
+    (url, server)
+}
+
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    
+    loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        
+        let text = String::from_utf8_lossy(&request);
+        let complete = text.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+            let length = head.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").and_then(|value| value.trim().parse().ok()))
+                .unwrap_or(0);
+            body.len() >= length
+        });
+        
+        if complete || n == 0 {
+            return text.into_owned();
+        }
+    }
+}
+
+/// Raw HTTP response that closes the connection
+pub(crate) fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n", status, body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    response
+}